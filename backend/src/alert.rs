@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use rocket::{tokio::{spawn, time::sleep}, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, events::{self, EventBus}, kitchen::QueueRequest, request::{COMPLETED, IN_KITCHEN, PENDING}, staff, station::STATION_OF, utils::{get_time, ValueInt}, validation::Validation};
+
+pub const DEFAULT_THRESHOLDS: [(i32, i32); 2] = [(PENDING, 20 * 60), (IN_KITCHEN, 30 * 60)];
+// (state, seconds) used when nothing is configured for it
+pub const SCAN_INTERVAL: u64 = 30;
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Threshold {
+    pub state: i32,
+    pub station: i32,
+    // -1 -> every station without its own threshold
+    pub seconds: i32
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Breach {
+    pub id: i32,
+    pub request: i32,
+    pub desk: String,
+    pub state: i32,
+    pub station: i32,
+    pub threshold: i32,
+    pub entered: i32,
+    // when the request entered the state it overstayed
+    pub detected: i32,
+    pub resolved: Option<i32>
+    // when it left that state, None while its still stuck
+}
+
+pub struct Alert;
+impl Alert {
+    // CREATE TABLE sla_threshold(state int, station int, seconds int, primary key(state, station));
+    // CREATE TABLE sla_breach(id integer primary key autoincrement, request int, desk varchar, state int, station int, threshold int, entered int, detected int, resolved int);
+
+    pub async fn set_threshold(db: &Pool<Sqlite>, state: i32, station: i32, seconds: i32) -> Result {
+        // seconds 0 removes the threshold
+        if !(PENDING..COMPLETED).contains(&state) {
+            return Result::StateInvalid;
+        }
+
+        if seconds <= 0 {
+            sqlx::query("delete from sla_threshold where state = $1 and station = $2;")
+                .bind(state)
+                .bind(station)
+                .execute(db)
+                .await
+                .unwrap();
+            return Result::Success;
+        }
+
+        sqlx::query("insert or replace into sla_threshold(state, station, seconds) values($1, $2, $3);")
+            .bind(state)
+            .bind(station)
+            .bind(seconds)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn fetch_thresholds(db: &Pool<Sqlite>) -> Vec<Threshold> {
+        sqlx::query_as("select * from sla_threshold order by state, station;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    fn threshold(thresholds: &[Threshold], state: i32, station: i32) -> Option<i32> {
+        // station's own -> every station -> built in default
+        thresholds.iter().find(|t| t.state == state && t.station == station)
+            .or_else(|| thresholds.iter().find(|t| t.state == state && t.station == -1))
+            .map(|t| t.seconds)
+            .or_else(|| DEFAULT_THRESHOLDS.iter().find(|t| t.0 == state).map(|t| t.1))
+    }
+
+    pub async fn scan(db: &Pool<Sqlite>) -> std::result::Result<Vec<i32>, sqlx::Error> {
+        // returns the requests that just went overdue
+        // errors go back to run() rather than panicking, a locked db just means waiting for the next scan
+        let now = get_time();
+
+        // requests that moved on, or are gone, close their breach
+        sqlx::query("update sla_breach set resolved = $1 where resolved is null and not exists (select 1 from request where request.id = sla_breach.request and request.state = sla_breach.state and request.held = 0);")
+            .bind(now)
+            .execute(db)
+            .await?;
+
+        let thresholds = sqlx::query_as::<_, Threshold>("select * from sla_threshold order by state, station;")
+            .fetch_all(db)
+            .await?;
+        let active = sqlx::query_as::<_, QueueRequest>(&format!(
+            "select request.*, {STATION_OF} as station from request join dish on dish.id = request.dish left join species on species.id = dish.species where (request.state = $1 or request.state = $2) and request.held = 0;"
+        ))
+            .bind(PENDING)
+            .bind(IN_KITCHEN)
+            .fetch_all(db)
+            .await?;
+
+        let mut overdue = vec![];
+        for r in active {
+            let threshold = match Alert::threshold(&thresholds, r.request.state, r.station) {
+                Some(t) => t,
+                None => continue
+            };
+
+            // one request failing doesnt hold back the others, it is looked at again next scan
+            match Alert::check(db, &r, threshold, now).await {
+                Ok(true) => overdue.push(r.request.id),
+                Ok(false) => {},
+                Err(e) => println!("alert.rs; scan(); request {}; error: {e}", r.request.id)
+            }
+        }
+        Ok(overdue)
+    }
+
+    async fn check(db: &Pool<Sqlite>, r: &QueueRequest, threshold: i32, now: i32) -> std::result::Result<bool, sqlx::Error> {
+        // true -> the request just went overdue and its breach was recorded
+
+        // a held course only starts its clock once fired, so the later of the two counts
+        let entered = sqlx::query_as::<_, ValueInt>("select max(coalesce((select time from request_state where request = $1 and state = $2 order by id desc limit 1), 0), coalesce((select max(time) from course_fire where desk = $3 and course = $4), 0));")
+            .bind(r.request.id)
+            .bind(r.request.state)
+            .bind(&r.request.desk)
+            .bind(r.request.course)
+            .fetch_one(db)
+            .await?
+            .0 as i32;
+
+        if now - entered <= threshold {
+            return Ok(false);
+        }
+
+        let open = sqlx::query_as::<_, ValueInt>("select count(*) from sla_breach where request = $1 and state = $2 and resolved is null;")
+            .bind(r.request.id)
+            .bind(r.request.state)
+            .fetch_one(db)
+            .await?
+            .0;
+        if open > 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("insert into sla_breach(request, desk, state, station, threshold, entered, detected, resolved) values($1, $2, $3, $4, $5, $6, $7, null);")
+            .bind(r.request.id)
+            .bind(&r.request.desk)
+            .bind(r.request.state)
+            .bind(r.station)
+            .bind(threshold)
+            .bind(entered)
+            .bind(now)
+            .execute(db)
+            .await?;
+
+        Ok(true)
+    }
+
+    pub async fn run(db: Pool<Sqlite>, bus: EventBus) {
+        // background task, started once the server is up
+        loop {
+            // run in its own task so even a panic further down is logged and scanning carries on
+            let (db, bus) = (db.clone(), bus.clone());
+            let scanned = spawn(async move {
+                match Alert::scan(&db).await {
+                    Ok(overdue) => {
+                        for id in overdue {
+                            bus.publish_id(&db, events::OVERDUE, id).await;
+                        }
+                    },
+                    Err(e) => println!("alert.rs; run(); error: {e}")
+                }
+            }).await;
+            if let Err(e) = scanned {
+                println!("alert.rs; run(); error: {e}");
+            }
+            sleep(Duration::from_secs(SCAN_INTERVAL)).await;
+        }
+    }
+
+    pub async fn active(db: &Pool<Sqlite>, station: Option<i32>) -> Vec<Breach> {
+        sqlx::query_as("select * from sla_breach where resolved is null and ($1 is null or station = $1) order by entered;")
+            .bind(station)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn breaches(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Breach> {
+        sqlx::query_as("select * from sla_breach where detected >= $1 and detected < $2 order by id;")
+            .bind(from)
+            .bind(to)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[get("/<state>/<seconds>?<station>")]
+pub async fn threshold(db: &State<Pool<Sqlite>>, state: i32, seconds: i32, station: Option<i32>) -> String {
+    Alert::set_threshold(db.inner(), state, station.unwrap_or(-1), seconds).await.to_string()
+}
+
+#[get("/")]
+pub async fn thresholds(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Alert::fetch_thresholds(db.inner()).await).unwrap()
+}
+
+#[get("/?<from>&<to>")]
+pub async fn breaches(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Alert::breaches(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}
+
+#[post("/?<station>", data="<login>")]
+pub async fn active(db: &State<Pool<Sqlite>>, login: String, station: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Alert::active(db, station).await).unwrap()
+}
@@ -0,0 +1,64 @@
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::{callback_result::Result, request::Request, tag::Tag};
+
+pub struct Allergy;
+impl Allergy {
+    pub async fn resolve(db: &mut SqliteConnection, names: &[String]) -> std::result::Result<Vec<i32>, Result> {
+        // allergen names as the guest picked them -> tag ids, anything not on the allergen list is refused
+        let mut ids = vec![];
+        for name in names {
+            match Tag::fetch_by_name(&mut *db, name).await {
+                Some(t) if t.kind == 0 => {
+                    if !ids.contains(&t.id) {
+                        ids.push(t.id);
+                    }
+                },
+                _ => {
+                    return Err(Result::AllergenDoesntExist);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    pub async fn conflicts(db: &mut SqliteConnection, dish: i32, variant: &[Option<usize>], declared: &[i32]) -> Vec<Tag> {
+        // declared allergens the dish still contains once the chosen options are applied
+        Tag::effective(db, dish, variant).await
+            .into_iter()
+            .filter(|t| declared.contains(&t.id))
+            .collect()
+    }
+
+    pub async fn check(db: &mut SqliteConnection, dish: i32, variant: &[Option<usize>], names: &[String], confirm: bool) -> std::result::Result<Vec<i32>, Result> {
+        // a conflict is only let through once the guest (or waiter) confirms it
+        let ids = Allergy::resolve(&mut *db, names).await?;
+        if !confirm && !Allergy::conflicts(&mut *db, dish, variant, &ids).await.is_empty() {
+            return Err(Result::AllergenConflict);
+        }
+        Ok(ids)
+    }
+
+    pub async fn of(db: &Pool<Sqlite>, request: &Request) -> (Vec<Tag>, Vec<Tag>) {
+        // (declared allergens, the ones among them the dish contains)
+        let declared = serde_json::from_str::<Vec<i32>>(&request.allergens).unwrap_or_default();
+        if declared.is_empty() {
+            return (vec![], vec![]);
+        }
+
+        let mut allergies = vec![];
+        for id in declared.iter() {
+            if let Some(t) = Tag::fetch(db, *id).await {
+                allergies.push(t);
+            }
+        }
+
+        let variant = serde_json::from_str::<Vec<Option<usize>>>(&request.variant).unwrap_or_default();
+        (allergies, Allergy::conflicts(&mut db.acquire().await.unwrap(), request.dish, &variant, &declared).await)
+    }
+
+    pub async fn attach(db: &Pool<Sqlite>, mut request: Request) -> Request {
+        (request.allergies, request.conflicts) = Allergy::of(db, &request).await;
+        request
+    }
+}
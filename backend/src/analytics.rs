@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, dish::Dish, eta::MIN_SAMPLES, request::{COMPLETED, IN_KITCHEN, PENDING}, station::{Station, STATION_OF}, utils::utc_offset};
+
+pub const DISH: &str = "dish";
+pub const STATION: &str = "station";
+pub const HOUR: &str = "hour";
+pub const GROUPS: [&str; 3] = [DISH, STATION, HOUR];
+
+#[derive(FromRow, Debug)]
+struct Timestamps {
+    dish: i32,
+    station: i32,
+    pending: i32,
+    accepted: Option<i32>,
+    completed: Option<i32>,
+    fired: i32
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+    // seconds
+    pub count: i64,
+    pub average: i64,
+    pub p50: i64,
+    pub p90: i64,
+    pub p95: i64,
+    pub max: i64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Performance {
+    pub id: i32,
+    // dish id, station id (-1 -> unrouted) or local hour of day
+    pub name: String,
+    pub accept: Stats,
+    // waiting until the kitchen takes it
+    pub cook: Stats,
+    // taken until bumped as done
+    pub serve: Stats
+    // the two together, from when the kitchen could first see it
+}
+
+pub fn stats(mut times: Vec<i64>) -> Stats {
+    // nearest rank percentiles
+    if times.is_empty() {
+        return Stats::default();
+    }
+    times.sort();
+    let n = times.len();
+    let rank = |p: usize| times[((p * n).div_ceil(100)).clamp(1, n) - 1];
+
+    Stats {
+        count: n as i64,
+        average: times.iter().sum::<i64>() / n as i64,
+        p50: rank(50),
+        p90: rank(90),
+        p95: rank(95),
+        max: times[n - 1]
+    }
+}
+
+pub struct Analytics;
+impl Analytics {
+    async fn timestamps(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Timestamps> {
+        // completed requests ordered in the range, with the last time each state was entered
+        // a recall takes the later state's row away, so the last one is the one that stuck
+        sqlx::query_as(&format!(
+            "select request.dish, {STATION_OF} as station, coalesce((select max(time) from request_state where request = request.id and state = $3), request.created) as pending, (select max(time) from request_state where request = request.id and state = $4) as accepted, (select max(time) from request_state where request = request.id and state = $5) as completed, coalesce((select max(time) from course_fire where course_fire.desk = request.desk and course_fire.course = request.course), 0) as fired from request join dish on dish.id = request.dish left join species on species.id = dish.species where request.state = $5 and request.created >= $1 and request.created < $2;"
+        ))
+            .bind(from)
+            .bind(to)
+            .bind(PENDING)
+            .bind(IN_KITCHEN)
+            .bind(COMPLETED)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn kitchen(db: &Pool<Sqlite>, group: &str, from: i32, to: i32) -> std::result::Result<Vec<Performance>, Result> {
+        if !GROUPS.contains(&group) {
+            return Err(Result::ReportInvalid);
+        }
+
+        let mut groups = BTreeMap::<i32, (Vec<i64>, Vec<i64>, Vec<i64>)>::new();
+        for t in Analytics::timestamps(db, from, to).await {
+            let (accepted, completed) = match (t.accepted, t.completed) {
+                (Some(a), Some(c)) if c >= a => (a, c),
+                // bumped straight to done, or the log doesnt add up
+                _ => continue
+            };
+            // a held course only shows up in the kitchen once its fired
+            let visible = if t.fired <= accepted { t.pending.max(t.fired) } else { t.pending };
+            if accepted < visible {
+                continue;
+            }
+
+            let key = match group {
+                DISH => t.dish,
+                STATION => t.station,
+                _ => (visible + utc_offset()).rem_euclid(86400) / 3600
+            };
+            let g = groups.entry(key).or_default();
+            g.0.push((accepted - visible) as i64);
+            g.1.push((completed - accepted) as i64);
+            g.2.push((completed - visible) as i64);
+        }
+
+        let mut rows = vec![];
+        for (id, (accept, cook, serve)) in groups {
+            let name = match group {
+                DISH => Dish::fetch(db, id).await.map(|d| d.name).unwrap_or(format!("dish #{id}")),
+                STATION if id == -1 => "unrouted".to_string(),
+                STATION => Station::fetch(db, id).await.map(|s| s.name).unwrap_or(format!("station #{id}")),
+                _ => format!("{id:02}:00")
+            };
+            rows.push(Performance { id, name, accept: stats(accept), cook: stats(cook), serve: stats(serve) });
+        }
+        Ok(rows)
+    }
+
+    pub async fn slowest(db: &Pool<Sqlite>, from: i32, to: i32, limit: usize, min_samples: i64) -> Vec<Performance> {
+        // dishes by how long the slow end of their orders take, p90 of serve
+        // too few samples and one bad night decides it, so those are left out
+        let mut rows = Analytics::kitchen(db, DISH, from, to).await.unwrap_or_default()
+            .into_iter()
+            .filter(|r| r.serve.count >= min_samples)
+            .collect::<Vec<Performance>>();
+        rows.sort_by(|a, b| b.serve.p90.cmp(&a.serve.p90).then(b.serve.average.cmp(&a.serve.average)).then(a.id.cmp(&b.id)));
+        rows.truncate(limit);
+        rows
+    }
+}
+
+#[get("/<group>?<from>&<to>")]
+pub async fn kitchen(db: &State<Pool<Sqlite>>, group: String, from: Option<i32>, to: Option<i32>) -> String {
+    match Analytics::kitchen(db.inner(), &group, from.unwrap_or(0), to.unwrap_or(i32::MAX)).await {
+        Ok(r) => serde_json::to_string(&r).unwrap(),
+        Err(e) => e.to_string()
+    }
+}
+
+#[get("/?<from>&<to>&<limit>&<min_samples>")]
+pub async fn slowest(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>, limit: Option<usize>, min_samples: Option<i64>) -> String {
+    serde_json::to_string(&Analytics::slowest(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX), limit.unwrap_or(10), min_samples.unwrap_or(MIN_SAMPLES as i64)).await).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_is_all_zero() {
+        let s = stats(vec![]);
+        assert_eq!((s.count, s.average, s.p50, s.p90, s.p95, s.max), (0, 0, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn single_sample_is_every_rank() {
+        let s = stats(vec![42]);
+        assert_eq!((s.count, s.average, s.p50, s.p90, s.p95, s.max), (1, 42, 42, 42, 42, 42));
+    }
+
+    #[test]
+    fn nearest_rank_on_one_to_a_hundred() {
+        // given backwards on purpose, stats sorts them
+        let s = stats((1..=100).rev().collect());
+        assert_eq!((s.count, s.average, s.p50, s.p90, s.p95, s.max), (100, 50, 50, 90, 95, 100));
+    }
+
+    #[test]
+    fn nearest_rank_rounds_up() {
+        // ranks ceil(p * n / 100) -> 5, 9, 10 of 10
+        let s = stats(vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+        assert_eq!((s.p50, s.p90, s.p95), (50, 90, 100));
+
+        // ranks 2, 3, 3 of 3
+        let s = stats(vec![300, 100, 200]);
+        assert_eq!((s.p50, s.p90, s.p95, s.average), (200, 300, 300, 200));
+    }
+}
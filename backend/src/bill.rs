@@ -0,0 +1,330 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, desk::Desk, discount::{BillDiscount, Discount}, payment::Payment, refund::Refund, request::{Request, CANCELLED}, session::Session, species::Species, staff, ticket::Ticket, utils::{decode_uri, get_time, ValueInt, ValueString}, validation::Validation};
+
+pub const DEFAULT_SERVICE_CHARGE: i32 = 1000;
+// basis points (1000 -> 10%), override with ROCKET_SERVICE_CHARGE or service_charge in Rocket.toml
+
+pub struct BillConfig {
+    pub service_charge: i32
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct TaxRate {
+    pub category: i32,
+    pub name: String,
+    pub rate: i32
+    // basis points, 600 -> 6%
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BillLine {
+    pub request: i32,
+    pub dish: i32,
+    pub name: String,
+    pub size: String,
+    pub options: Vec<String>,
+    pub quantity: i32,
+    pub unit_price: i64,
+    pub amount: i64,
+    pub discount: i64,
+    // taken off this line by pricing rules, see discount.rs
+    pub species: i32,
+    pub tax_category: i32
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BillTax {
+    pub category: i32,
+    pub name: String,
+    pub rate: i32,
+    pub taxable: i64,
+    pub amount: i64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bill {
+    // every amount is in minor units (sen)
+    pub session: i32,
+    pub desk: String,
+    pub opened: i32,
+    pub closed: Option<i32>,
+    pub lines: Vec<BillLine>,
+    // ordered by request id so the same session always renders the same
+    pub subtotal: i64,
+    pub discounts: Vec<BillDiscount>,
+    // every rule that applied, in the order it was applied
+    pub discount: i64,
+    pub service_charge_rate: i32,
+    pub service_charge: i64,
+    pub taxes: Vec<BillTax>,
+    pub tax: i64,
+    pub total: i64,
+    pub paid: i64,
+    pub refunded: i64,
+    // money given back, see refund.rs
+    pub adjustments: i64,
+    // refunded lines at their full value, those lines are no longer owed whatever was paid back
+    pub outstanding: i64
+}
+
+pub fn apply_rate(amount: i64, rate: i32) -> i64 {
+    // amount * basis points, rounded half up to the nearest minor unit
+    (amount * rate as i64 + 5000).div_euclid(10000)
+}
+
+impl Bill {
+    // CREATE TABLE tax_rate(category int primary key, name varchar, rate int);
+    // species.tax_category int default 0
+    // CREATE TABLE bill_snapshot(session integer primary key, data varchar, taken int);
+    // data -> the Bill as it stood when the session closed, later price, rate or rule changes leave it alone
+
+    pub async fn set_tax_rate(db: &Pool<Sqlite>, category: i32, name: String, rate: i32) -> Result {
+        if !(0..=10000).contains(&rate) {
+            return Result::RateInvalid;
+        }
+
+        sqlx::query("insert or replace into tax_rate(category, name, rate) values($1, $2, $3);")
+            .bind(category)
+            .bind(name)
+            .bind(rate)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn fetch_tax_rates(db: &Pool<Sqlite>) -> Vec<TaxRate> {
+        sqlx::query_as("select * from tax_rate order by category;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn assign_species(db: &Pool<Sqlite>, species: i32, category: i32) -> Result {
+        if Species::fetch(db, species).await.is_none() {
+            return Result::DoesntExist;
+        }
+
+        sqlx::query("update species set tax_category = $1 where id = $2;")
+            .bind(category)
+            .bind(species)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    async fn species(db: &Pool<Sqlite>, dish: i32) -> i32 {
+        sqlx::query_as::<_, ValueInt>("select coalesce((select species from dish where id = $1), -1);")
+            .bind(dish)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0 as i32
+    }
+
+    async fn tax_category(db: &Pool<Sqlite>, dish: i32) -> i32 {
+        sqlx::query_as::<_, ValueInt>("select coalesce((select species.tax_category from dish join species on species.id = dish.species where dish.id = $1), 0);")
+            .bind(dish)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0 as i32
+    }
+
+    pub async fn billable(db: &Pool<Sqlite>, session: i32) -> Vec<Request> {
+        sqlx::query_as("select * from request where session = $1 and state != $2 order by id;")
+            .bind(session)
+            .bind(CANCELLED)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn compute(db: &Pool<Sqlite>, session: i32, config: &BillConfig) -> Option<Bill> {
+        // only the money that moved is read again, see charged
+        let s = Session::fetch(db, session).await?;
+        let mut bill = Bill::charged(db, &s, config).await;
+
+        bill.paid = Payment::paid(db, s.id).await;
+        bill.refunded = Refund::refunded_session(db, s.id).await;
+        bill.adjustments = Refund::adjusted(db, s.id).await;
+        bill.outstanding = bill.total - bill.adjustments - bill.paid + bill.refunded;
+        Some(bill)
+    }
+
+    pub async fn charged(db: &Pool<Sqlite>, s: &Session, config: &BillConfig) -> Bill {
+        // what the session is charged, a closed one whatever its snapshot says
+        // paid, refunded and adjustments are left at 0
+        let mut bill = match s.closed {
+            Some(_) => match Bill::fetch_snapshot(db, s.id).await {
+                Some(b) => b,
+                None => Bill::charges(db, s, config).await
+            },
+            None => Bill::charges(db, s, config).await
+        };
+        bill.opened = s.opened;
+        bill.closed = s.closed;
+        (bill.paid, bill.refunded, bill.adjustments, bill.outstanding) = (0, 0, 0, bill.total);
+        bill
+    }
+
+    async fn charges(db: &Pool<Sqlite>, s: &Session, config: &BillConfig) -> Bill {
+        // what the session owes at todays prices, rates and rules, the money side is left to compute
+        let requests = Bill::billable(db, s.id).await;
+        let described = Ticket::lines(db, &requests).await;
+
+        let mut lines = vec![];
+        for (r, d) in requests.iter().zip(described) {
+            lines.push(BillLine {
+                request: r.id,
+                dish: r.dish,
+                name: d.dish,
+                size: d.size,
+                options: d.options,
+                quantity: r.quantity,
+                unit_price: r.price,
+                amount: r.price * r.quantity as i64,
+                discount: 0,
+                species: Bill::species(db, r.dish).await,
+                tax_category: Bill::tax_category(db, r.dish).await
+            });
+        }
+
+        let discounts = Discount::apply(db, s, &requests, &mut lines).await;
+
+        let subtotal = lines.iter().map(|l| l.amount).sum::<i64>();
+        let discount = discounts.iter().map(|d| d.amount).sum::<i64>();
+        // the service charge itself isnt taxed
+        let service_charge = apply_rate(subtotal - discount, config.service_charge);
+
+        let mut taxes = vec![];
+        for rate in Bill::fetch_tax_rates(db).await {
+            let taxable = lines.iter().filter(|l| l.tax_category == rate.category).map(|l| l.amount - l.discount).sum::<i64>();
+            if taxable == 0 {
+                continue;
+            }
+
+            taxes.push(BillTax {
+                category: rate.category,
+                name: rate.name,
+                rate: rate.rate,
+                taxable,
+                amount: apply_rate(taxable, rate.rate)
+            });
+        }
+        let tax = taxes.iter().map(|t| t.amount).sum::<i64>();
+        let total = subtotal - discount + service_charge + tax;
+
+        Bill {
+            session: s.id,
+            desk: s.desk.clone(),
+            opened: s.opened,
+            closed: s.closed,
+            lines,
+            subtotal,
+            discounts,
+            discount,
+            service_charge_rate: config.service_charge,
+            service_charge,
+            taxes,
+            tax,
+            total,
+            paid: 0,
+            refunded: 0,
+            adjustments: 0,
+            outstanding: total
+        }
+    }
+
+    pub async fn snapshot(db: &Pool<Sqlite>, session: i32, config: &BillConfig) {
+        // taken as the session closes, sessions closed before there were snapshots are still worked out live
+        let s = match Session::fetch(db, session).await {
+            Some(s) => s,
+            None => {
+                return;
+            }
+        };
+        let bill = Bill::charges(db, &s, config).await;
+
+        sqlx::query("insert or replace into bill_snapshot(session, data, taken) values($1, $2, $3);")
+            .bind(session)
+            .bind(serde_json::to_string(&bill).unwrap())
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn drop_snapshot(db: &Pool<Sqlite>, session: i32) {
+        // a reopened session is live again until it next closes
+        sqlx::query("delete from bill_snapshot where session = $1;")
+            .bind(session)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    async fn fetch_snapshot(db: &Pool<Sqlite>, session: i32) -> Option<Bill> {
+        let data = sqlx::query_as::<_, ValueString>("select data from bill_snapshot where session = $1;")
+            .bind(session)
+            .fetch_optional(db)
+            .await
+            .unwrap()?;
+        match serde_json::from_str(&data.0) {
+            Ok(b) => Some(b),
+            Err(e) => {
+                println!("bill.rs; fetch_snapshot({session}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn current(db: &Pool<Sqlite>, desk: &str, config: &BillConfig) -> Option<Bill> {
+        let s = Session::current(db, desk).await?;
+        Bill::compute(db, s.id, config).await
+    }
+}
+
+#[get("/<category>/<name>/<rate>")]
+pub async fn tax_rate(db: &State<Pool<Sqlite>>, category: i32, name: String, rate: i32) -> String {
+    Bill::set_tax_rate(db.inner(), category, decode_uri(name), rate).await.to_string()
+}
+
+#[get("/")]
+pub async fn tax_rates(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Bill::fetch_tax_rates(db.inner()).await).unwrap()
+}
+
+#[get("/<species>/<category>")]
+pub async fn species_tax(db: &State<Pool<Sqlite>>, species: i32, category: i32) -> String {
+    Bill::assign_species(db.inner(), species, category).await.to_string()
+}
+
+#[get("/<session>")]
+pub async fn session(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, session: i32) -> String {
+    serde_json::to_string(&Bill::compute(db.inner(), session, config.inner()).await).unwrap()
+}
+
+#[post("/", data="<table>")]
+pub async fn fetch(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, table: String) -> String {
+    let db = db.inner();
+    match Desk::fetch(db, &table).await {
+        Some(d) => serde_json::to_string(&Bill::current(db, &d.name, config.inner()).await).unwrap(),
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[post("/<desk>", data="<login>")]
+pub async fn table(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, login: String, desk: String) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Bill::current(db, &decode_uri(desk), config.inner()).await).unwrap()
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumString};
 
-#[derive(Clone, Copy, Serialize, Deserialize, Display, EnumString)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Display, EnumString)]
 pub enum Result {
     Success,
     DoesntExist,
@@ -12,7 +12,45 @@ pub enum Result {
 
     VariantDoesntExist,
     SizeDoesntExist,
+    QuantityInvalid,
+    CartInvalid,
+    IdempotencyInProgress,
+
+    LocaleDoesntExist,
+
+    PictureTooLarge,
+    PictureTypeUnsupported,
+    PictureSaveFailed,
+
+    StateInvalid,
+    CourseDoesntExist,
+    PrepTimeInvalid,
+    ReasonDoesntExist,
+    PriorityInvalid,
+    AllergenDoesntExist,
+    AllergenConflict,
+
+    PriceInvalid,
+    RateInvalid,
+    SeatInvalid,
+    SplitInvalid,
+
+    MethodDoesntExist,
+    PaymentInvalid,
+    PaymentDeclined,
+    BillSettled,
+    RefundInvalid,
+    TipInvalid,
+    GiftCardInvalid,
+    GiftCardLocked,
+
+    ReportInvalid,
+    RuleInvalid,
+    PromoInvalid,
+    PromoExhausted,
+    PromoExceedsBalance,
 
     NoPermission,
+    RoleDoesntExist,
     NoTable
 }
@@ -0,0 +1,171 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, desk::Desk, dish::Dish, request::Request, species::Species, staff, tag::Tag, utils::{get_time, ValueInt}, validation::Validation};
+
+pub const RETENTION: i32 = 7 * 24 * 60 * 60;
+// seconds of change log kept, clients further behind than this get a snapshot
+
+// the log is filled by triggers, so every write counts no matter which route made it
+// CREATE TABLE change(seq integer primary key autoincrement, entity varchar, key varchar, op varchar, scope varchar, time int);
+// CREATE TRIGGER desk_insert after insert on desk begin insert into change(entity, key, op, scope, time) values('desk', new.name, 'insert', null, strftime('%s', 'now')); end;
+// CREATE TRIGGER desk_update after update on desk begin insert into change(entity, key, op, scope, time) values('desk', new.name, 'update', null, strftime('%s', 'now')); end;
+// CREATE TRIGGER desk_delete after delete on desk begin insert into change(entity, key, op, scope, time) values('desk', old.name, 'delete', null, strftime('%s', 'now')); end;
+// same three for dish, species and tag keyed on id, and for request keyed on id with scope = desk
+// a dish or species is served with its tags, translations and picture, so those log an update of it
+// CREATE TRIGGER dish_tag_insert after insert on dish_tag begin insert into change(entity, key, op, scope, time) values('dish', new.dish, 'update', null, strftime('%s', 'now')); end;
+// CREATE TRIGGER picture_insert after insert on picture begin insert into change(entity, key, op, scope, time) values(case new.kind when 0 then 'species' else 'dish' end, new.target, 'update', null, strftime('%s', 'now')); end;
+// same three (insert, update, delete, using old on delete) for dish_tag and option_tag keyed on dish, and for translation and picture keyed on target
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: i64,
+    pub entity: String,
+    // desk, dish, species, tag, request
+    pub key: String,
+    // desk name, otherwise the id
+    pub op: String,
+    // insert, update, delete
+    pub scope: Option<String>,
+    // the desk a request belongs to, null for everything else
+    pub time: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeEntry {
+    #[serde(flatten)]
+    pub change: Change,
+    pub data: Option<serde_json::Value>
+    // the row as it is now, null once deleted
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub desks: Vec<Desk>,
+    pub dishes: Vec<Dish>,
+    pub species: Vec<Species>,
+    pub tags: Vec<Tag>,
+    pub requests: Vec<Request>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Feed {
+    pub seq: i64,
+    // pass this back as since next time
+    pub snapshot: Option<Snapshot>,
+    // only when since was too old (or 0), replaces everything the client had
+    pub changes: Vec<ChangeEntry>
+    // oldest first, one entry per row holding its latest change
+}
+
+pub struct ChangeLog;
+impl ChangeLog {
+    async fn current(db: &Pool<Sqlite>) -> i64 {
+        sqlx::query_as::<_, ValueInt>("select coalesce((select seq from sqlite_sequence where name = 'change'), 0);")
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    async fn oldest(db: &Pool<Sqlite>) -> Option<i64> {
+        sqlx::query_as::<_, ValueInt>("select seq from change order by seq limit 1;")
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .map(|x| x.0)
+    }
+
+    async fn data(db: &Pool<Sqlite>, change: &Change) -> Option<serde_json::Value> {
+        if change.op == "delete" {
+            return None;
+        }
+
+        let id = change.key.parse::<i32>().unwrap_or(-1);
+        match change.entity.as_str() {
+            "desk" => Desk::fetch(db, &change.key).await.map(|x| serde_json::to_value(x).unwrap()),
+            "dish" => Dish::fetch(db, id).await.map(|x| serde_json::to_value(x).unwrap()),
+            "species" => Species::fetch(db, id).await.map(|x| serde_json::to_value(x).unwrap()),
+            "tag" => Tag::fetch(db, id).await.map(|x| serde_json::to_value(x).unwrap()),
+            "request" => Request::fetch(db, id).await.map(|x| serde_json::to_value(x).unwrap()),
+            _ => None
+        }
+    }
+
+    pub async fn feed(db: &Pool<Sqlite>, since: i64, desk: Option<String>) -> Feed {
+        // desk None -> staff, every request
+        // desk Some -> a table, only its own requests and its own desk
+        // a desk's name is what table routes check, so one table must never be shown another's
+        sqlx::query("delete from change where time < $1;")
+            .bind(get_time() - RETENTION)
+            .execute(db)
+            .await
+            .unwrap();
+
+        let seq = ChangeLog::current(db).await;
+        let oldest = ChangeLog::oldest(db).await.unwrap_or(seq + 1);
+
+        // since == seq is an up to date client, anything outside [oldest - 1, seq] cant be served as a delta
+        if since <= 0 || since < oldest - 1 || since > seq {
+            let (desks, requests) = match &desk {
+                Some(d) => (Desk::fetch(db, d).await.into_iter().collect(), Request::fetch_by_desk(db, d).await),
+                None => (Desk::fetch_all(db).await, Request::fetch_all(db).await)
+            };
+
+            return Feed {
+                seq,
+                snapshot: Some(Snapshot {
+                    desks,
+                    dishes: Dish::fetch_all(db).await,
+                    species: Species::fetch_all(db).await,
+                    tags: Tag::fetch_all(db).await,
+                    requests
+                }),
+                changes: vec![]
+            };
+        }
+
+        // latest change per row, rows touched several times only show up once
+        let changes = sqlx::query_as::<_, Change>("select * from change where seq in (select max(seq) from change where seq > $1 and seq <= $2 and ($3 is null or entity not in ('request', 'desk') or (entity = 'request' and scope = $3) or (entity = 'desk' and key = $3)) group by entity, key) order by seq;")
+            .bind(since)
+            .bind(seq)
+            .bind(&desk)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut result = vec![];
+        for c in changes {
+            let data = ChangeLog::data(db, &c).await;
+            result.push(ChangeEntry {
+                change: c,
+                data
+            });
+        }
+
+        Feed {
+            seq,
+            snapshot: None,
+            changes: result
+        }
+    }
+}
+
+#[post("/?<since>", data="<table>")]
+pub async fn for_table(db: &State<Pool<Sqlite>>, table: String, since: Option<i64>) -> String {
+    let db = db.inner();
+    match Desk::fetch(db, &table).await {
+        Some(d) => serde_json::to_string(&ChangeLog::feed(db, since.unwrap_or(0), Some(d.name)).await).unwrap(),
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[post("/?<since>", data="<login>")]
+pub async fn for_staff(db: &State<Pool<Sqlite>>, login: String, since: Option<i64>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&ChangeLog::feed(db, since.unwrap_or(0), None).await).unwrap()
+}
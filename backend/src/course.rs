@@ -0,0 +1,251 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Executor, Pool, Sqlite};
+
+use crate::{callback_result::Result, desk::Desk, dish::Dish, events::{self, EventBus}, printer::Printer, request::{CANCELLED, COMPLETED, PENDING}, species::Species, staff, utils::{decode_uri, get_time, ValueInt}, validation::Validation};
+
+pub const STARTER: i32 = 0;
+pub const MAIN: i32 = 1;
+pub const DESSERT: i32 = 2;
+pub const COURSES: [i32; 3] = [STARTER, MAIN, DESSERT];
+
+pub const COURSE_OF: &str = "(case when dish.course != -1 then dish.course else coalesce(species.course, 1) end)";
+// sql for the course a dish is served in, same joins as STATION_OF
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct CourseFire {
+    pub id: i32,
+    pub desk: String,
+    pub course: i32,
+    pub time: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CourseTiming {
+    #[serde(flatten)]
+    pub fire: CourseFire,
+    pub gap: Option<i32>
+    // seconds since the previous course was fired, None for the first
+}
+
+pub struct Course;
+impl Course {
+    // CREATE TABLE course_fire(id integer primary key autoincrement, desk varchar, course int, time int);
+    // species.course int default 1, dish.course int default -1, request.course int default 1, request.held int default 0
+
+    pub async fn of_dish<'e, E: Executor<'e, Database = Sqlite>>(db: E, dish: i32) -> i32 {
+        sqlx::query_as::<_, ValueInt>(&format!(
+            "select {COURSE_OF} from dish left join species on species.id = dish.species where dish.id = $1;"
+        ))
+            .bind(dish)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .map(|x| x.0 as i32)
+            .unwrap_or(MAIN)
+    }
+
+    pub async fn should_hold<'e, E: Executor<'e, Database = Sqlite>>(db: E, desk: &str, course: i32, ordered_with: Option<i32>) -> bool {
+        // a course waits while the table still has an earlier one open
+        // ordered_with is the earliest course in the same cart, which isnt in the db yet
+        if ordered_with.is_some_and(|c| c < course) {
+            return true;
+        }
+
+        sqlx::query_as::<_, ValueInt>("select count(*) from request where desk = $1 and state < $2 and course < $3;")
+            .bind(desk)
+            .bind(COMPLETED)
+            .bind(course)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0 > 0
+    }
+
+    pub async fn fire(db: &Pool<Sqlite>, desk: String, course: Option<i32>) -> (Result, Vec<i32>) {
+        // course None -> the earliest course still held
+        if Desk::fetch(db, &desk).await.is_none() {
+            return (Result::NoTable, vec![]);
+        }
+
+        let course = match course {
+            Some(c) => c,
+            None => match sqlx::query_as::<_, ValueInt>("select min(course) from request where desk = $1 and held = 1 and state != $2;")
+                .bind(&desk)
+                .bind(CANCELLED)
+                .fetch_one(db)
+                .await {
+                Ok(c) => c.0 as i32,
+                Err(_) => {
+                    return (Result::DoesntExist, vec![]);
+                }
+            }
+        };
+
+        // a voided line keeps its held flag, it just never goes to the kitchen
+        let ids = sqlx::query_as::<_, ValueInt>("select id from request where desk = $1 and course = $2 and held = 1 and state != $3;")
+            .bind(&desk)
+            .bind(course)
+            .bind(CANCELLED)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0 as i32)
+            .collect::<Vec<i32>>();
+
+        if ids.is_empty() {
+            return (Result::DoesntExist, vec![]);
+        }
+
+        sqlx::query("update request set held = 0 where desk = $1 and course = $2 and held = 1 and state != $3;")
+            .bind(&desk)
+            .bind(course)
+            .bind(CANCELLED)
+            .execute(db)
+            .await
+            .unwrap();
+
+        sqlx::query("insert into course_fire(desk, course, time) values($1, $2, $3);")
+            .bind(&desk)
+            .bind(course)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap();
+
+        (Result::Success, ids)
+    }
+
+    pub async fn hold(db: &Pool<Sqlite>, desk: String, course: i32) -> (Result, Vec<i32>) {
+        // only lines the kitchen hasnt started on can be held back
+        if Desk::fetch(db, &desk).await.is_none() {
+            return (Result::NoTable, vec![]);
+        }
+
+        let ids = sqlx::query_as::<_, ValueInt>("select id from request where desk = $1 and course = $2 and held = 0 and state = $3;")
+            .bind(&desk)
+            .bind(course)
+            .bind(PENDING)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0 as i32)
+            .collect::<Vec<i32>>();
+
+        if ids.is_empty() {
+            return (Result::DoesntExist, vec![]);
+        }
+
+        sqlx::query("update request set held = 1 where desk = $1 and course = $2 and held = 0 and state = $3;")
+            .bind(&desk)
+            .bind(course)
+            .bind(PENDING)
+            .execute(db)
+            .await
+            .unwrap();
+
+        (Result::Success, ids)
+    }
+
+    pub async fn timing(db: &Pool<Sqlite>, desk: &str) -> Vec<CourseTiming> {
+        let fires = sqlx::query_as::<_, CourseFire>("select * from course_fire where desk = $1 order by id;")
+            .bind(desk)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut result: Vec<CourseTiming> = vec![];
+        for f in fires {
+            result.push(CourseTiming {
+                gap: result.last().map(|p| f.time - p.fire.time),
+                fire: f
+            });
+        }
+        result
+    }
+
+    pub async fn assign_species(db: &Pool<Sqlite>, species: i32, course: i32) -> Result {
+        if Species::fetch(db, species).await.is_none() {
+            return Result::DoesntExist;
+        }
+        if !COURSES.contains(&course) {
+            return Result::CourseDoesntExist;
+        }
+
+        sqlx::query("update species set course = $1 where id = $2;")
+            .bind(course)
+            .bind(species)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn assign_dish(db: &Pool<Sqlite>, dish: i32, course: i32) -> Result {
+        // course -1 sends the dish back to its species' course
+        if Dish::fetch(db, dish).await.is_none() {
+            return Result::DoesntExist;
+        }
+        if course != -1 && !COURSES.contains(&course) {
+            return Result::CourseDoesntExist;
+        }
+
+        sqlx::query("update dish set course = $1 where id = $2;")
+            .bind(course)
+            .bind(dish)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+}
+
+#[get("/<species>/<course>")]
+pub async fn assign_species(db: &State<Pool<Sqlite>>, species: i32, course: i32) -> String {
+    Course::assign_species(db.inner(), species, course).await.to_string()
+}
+
+#[get("/<dish>/<course>")]
+pub async fn assign_dish(db: &State<Pool<Sqlite>>, dish: i32, course: i32) -> String {
+    Course::assign_dish(db.inner(), dish, course).await.to_string()
+}
+
+#[post("/<desk>?<course>", data="<login>")]
+pub async fn fire(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, desk: String, course: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    let (result, ids) = Course::fire(db, decode_uri(desk), course).await;
+    for id in ids.iter() {
+        bus.publish_id(db, events::FIRED, *id).await;
+    }
+    Printer::queue_requests(db, &ids).await;
+    result.to_string()
+}
+
+#[post("/<desk>/<course>", data="<login>")]
+pub async fn hold(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, desk: String, course: i32) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    let (result, ids) = Course::hold(db, decode_uri(desk), course).await;
+    for id in ids {
+        bus.publish_id(db, events::HELD, id).await;
+    }
+    result.to_string()
+}
+
+#[post("/<desk>", data="<login>")]
+pub async fn timing(db: &State<Pool<Sqlite>>, login: String, desk: String) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Course::timing(db, &decode_uri(desk)).await).unwrap()
+}
@@ -48,7 +48,7 @@ impl Desk {
 
     pub async fn fetch(db: &Pool<Sqlite>, name: &String) -> Option<Desk> {
         match sqlx::query_as("select * from desk where name = $1;")
-            .bind(&name)
+            .bind(name)
             .fetch_one(db)
             .await {
             Ok(d) => Some(d),
@@ -0,0 +1,473 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{bill::{apply_rate, Bill, BillConfig, BillLine}, callback_result::Result, desk::Desk, payment::Payment, request::Request, session::Session, split::allocate, utils::{get_time, ValueInt, utc_offset}};
+
+pub const PERCENT: i32 = 0;
+pub const FIXED: i32 = 1;
+pub const BUY_GET: i32 = 2;
+pub const KINDS: [i32; 3] = [PERCENT, FIXED, BUY_GET];
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct PricingRule {
+    pub id: i32,
+    pub name: String,
+    pub kind: i32,
+    // 0 -> percent off, value in basis points
+    // 1 -> fixed amount off, value in minor units (per unit on lines, once on the bill)
+    // 2 -> buy x get y free, the cheapest units go free
+    pub value: i64,
+    pub species: i32,
+    // -1 -> the whole bill, otherwise only lines of that species
+    pub buy: i32,
+    pub get: i32,
+    pub code: Option<String>,
+    // Some -> only once the promo code was entered for the session
+    pub starts: Option<i32>,
+    pub ends: Option<i32>,
+    // unix time, None -> open ended
+    pub from_minute: Option<i32>,
+    pub until_minute: Option<i32>,
+    // local minutes since midnight, happy hour 17:00-19:00 -> 1020, 1140
+    pub usage_limit: Option<i32>,
+    // how many sessions can use the code
+    pub active: bool,
+    pub created: i32
+    // 0 for rules made before this was kept
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BillDiscount {
+    pub rule: i32,
+    pub name: String,
+    pub request: Option<i32>,
+    // None -> taken off the whole bill
+    pub amount: i64
+}
+
+pub fn parse_minute(s: &str) -> Option<i32> {
+    // "17:30" -> 1050
+    let (h, m) = s.split_once(':')?;
+    let (h, m) = (h.trim().parse::<i32>().ok()?, m.trim().parse::<i32>().ok()?);
+    if !(0..24).contains(&h) || !(0..60).contains(&m) {
+        return None;
+    }
+    Some(h * 60 + m)
+}
+
+impl PricingRule {
+    fn running_at(&self, time: i32) -> bool {
+        self.starts.is_none_or(|s| time >= s) && self.ends.is_none_or(|e| time <= e)
+    }
+
+    fn applies_at(&self, time: i32) -> bool {
+        if !self.running_at(time) {
+            return false;
+        }
+
+        match (self.from_minute, self.until_minute) {
+            (Some(from), Some(until)) => {
+                let minute = (time + utc_offset()).rem_euclid(86400) / 60;
+                // a window like 22:00-02:00 goes past midnight
+                if from <= until { (from..until).contains(&minute) } else { minute >= from || minute < until }
+            },
+            _ => true
+        }
+    }
+
+    fn matches(&self, line: &BillLine) -> bool {
+        self.species < 0 || self.species == line.species
+    }
+}
+
+pub struct Discount;
+impl Discount {
+    // CREATE TABLE pricing_rule(id integer primary key autoincrement, name varchar, kind int, value int, species int, buy int, get int, code varchar, starts int, ends int, from_minute int, until_minute int, usage_limit int, active boolean);
+    // CREATE TABLE session_promo(session int, rule int, time int, primary key(session, rule));
+    // pricing_rule.created int default 0
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(db: &Pool<Sqlite>, name: String, kind: i32, value: i64, species: i32, buy: i32, get: i32, code: Option<String>, starts: Option<i32>, ends: Option<i32>, from_minute: Option<i32>, until_minute: Option<i32>, usage_limit: Option<i32>) -> Result {
+        if !KINDS.contains(&kind) {
+            return Result::RuleInvalid;
+        }
+        let valid = match kind {
+            PERCENT => (1..=10000).contains(&value),
+            FIXED => value > 0,
+            _ => buy > 0 && get > 0
+        };
+        if !valid || from_minute.is_some() != until_minute.is_some() || usage_limit.is_some_and(|l| l < 1) {
+            return Result::RuleInvalid;
+        }
+        if let Some(c) = &code {
+            if Discount::fetch_by_code(db, c).await.is_some() {
+                return Result::Exists;
+            }
+        }
+
+        sqlx::query("insert into pricing_rule(name, kind, value, species, buy, get, code, starts, ends, from_minute, until_minute, usage_limit, active, created) values($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, true, $13);")
+            .bind(name)
+            .bind(kind)
+            .bind(value)
+            .bind(species)
+            .bind(buy)
+            .bind(get)
+            .bind(code)
+            .bind(starts)
+            .bind(ends)
+            .bind(from_minute)
+            .bind(until_minute)
+            .bind(usage_limit)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn set_active(db: &Pool<Sqlite>, id: i32, active: bool) -> Result {
+        // rules are switched off rather than deleted so old bills still name them
+        // only bills still open change, closed ones keep what they were charged in bill_snapshot
+        if Discount::fetch(db, id).await.is_none() {
+            return Result::DoesntExist;
+        }
+
+        sqlx::query("update pricing_rule set active = $1 where id = $2;")
+            .bind(active)
+            .bind(id)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<PricingRule> {
+        match sqlx::query_as("select * from pricing_rule where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                println!("discount.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_by_code(db: &Pool<Sqlite>, code: &str) -> Option<PricingRule> {
+        sqlx::query_as("select * from pricing_rule where lower(code) = lower($1) and active = true;")
+            .bind(code)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<PricingRule> {
+        sqlx::query_as("select * from pricing_rule order by id;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn redeem(db: &Pool<Sqlite>, config: &BillConfig, desk: &str, code: &str) -> Result {
+        let session = match Session::current(db, desk).await {
+            Some(s) => s,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+        let rule = match Discount::fetch_by_code(db, code).await {
+            // the minute window isnt checked here, a happy hour code can be entered early and waits for it
+            Some(r) if r.running_at(get_time()) => r,
+            _ => {
+                return Result::PromoInvalid;
+            }
+        };
+
+        // counted and claimed in one statement so two tables cant take the last use together
+        let inserted = sqlx::query("insert or ignore into session_promo(session, rule, time) select $1, $2, $3 where $4 is null or (select count(*) from session_promo where rule = $2) < $4;")
+            .bind(session.id)
+            .bind(rule.id)
+            .bind(get_time())
+            .bind(rule.usage_limit)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+
+        if inserted == 0 && !Discount::promos(db, session.id).await.contains(&rule.id) {
+            return Result::PromoExhausted;
+        }
+        if inserted == 0 {
+            return Result::Exists;
+        }
+
+        // a code entered after part of the bill was paid can take it below what was already paid
+        // theres no credit to hold the difference, so the code is taken back off instead
+        let over = match Bill::compute(db, session.id, config).await {
+            Some(b) => b.total - b.adjustments + b.refunded < Payment::claimed(db, session.id).await,
+            None => false
+        };
+        if over {
+            sqlx::query("delete from session_promo where session = $1 and rule = $2;")
+                .bind(session.id)
+                .bind(rule.id)
+                .execute(db)
+                .await
+                .unwrap();
+            return Result::PromoExceedsBalance;
+        }
+
+        Result::Success
+    }
+
+    async fn promos(db: &Pool<Sqlite>, session: i32) -> Vec<i32> {
+        sqlx::query_as::<_, ValueInt>("select rule from session_promo where session = $1;")
+            .bind(session)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0 as i32)
+            .collect()
+    }
+
+    pub async fn apply(db: &Pool<Sqlite>, session: &Session, requests: &[Request], lines: &mut [BillLine]) -> Vec<BillDiscount> {
+        // lines and requests are in the same order
+        // a rule made after the session closed never reaches back into it, whatever its dates say
+        let promos = Discount::promos(db, session.id).await;
+        let until = session.closed.unwrap_or(get_time());
+        let rules = sqlx::query_as::<_, PricingRule>("select * from pricing_rule where active = true order by id;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.created < until && (r.code.is_none() || promos.contains(&r.id)))
+            .collect::<Vec<PricingRule>>();
+
+        Discount::apply_rules(&rules, session.opened, &requests.iter().map(|r| r.created).collect::<Vec<i32>>(), lines)
+    }
+
+    fn apply_rules(rules: &[PricingRule], opened: i32, created: &[i32], lines: &mut [BillLine]) -> Vec<BillDiscount> {
+        // created -> when each line was ordered, same order as lines
+        // line rules go first, then bill rules on what is left, each group by rule id
+        // so the same bill always comes out the same
+        let mut applied = vec![];
+        let net = |l: &BillLine| l.amount - l.discount;
+
+        for rule in rules.iter().filter(|r| r.kind == BUY_GET || r.species >= 0) {
+            // lines ordered during the rule's window, happy hour counts when the order went in
+            let eligible = (0..lines.len())
+                .filter(|i| rule.matches(&lines[*i]) && rule.applies_at(created[*i]))
+                .collect::<Vec<usize>>();
+            let mut off = vec![0; lines.len()];
+
+            if rule.kind == BUY_GET {
+                // every unit, dearest first, in groups of buy + get, the last get of each group are free
+                let mut units = eligible.iter()
+                    .flat_map(|i| std::iter::repeat_n(*i, lines[*i].quantity.max(0) as usize))
+                    .collect::<Vec<usize>>();
+                units.sort_by_key(|i| (-lines[*i].unit_price, lines[*i].request));
+
+                let group = (rule.buy + rule.get) as usize;
+                for chunk in units.chunks(group).filter(|c| c.len() == group) {
+                    for i in chunk.iter().skip(rule.buy as usize) {
+                        off[*i] += lines[*i].unit_price;
+                    }
+                }
+            } else {
+                for i in eligible {
+                    off[i] = match rule.kind {
+                        PERCENT => apply_rate(net(&lines[i]), rule.value as i32),
+                        _ => rule.value * lines[i].quantity as i64
+                    };
+                }
+            }
+
+            for (i, amount) in off.into_iter().enumerate() {
+                let amount = amount.min(net(&lines[i]));
+                if amount <= 0 {
+                    continue;
+                }
+                lines[i].discount += amount;
+                applied.push(BillDiscount { rule: rule.id, name: rule.name.clone(), request: Some(lines[i].request), amount });
+            }
+        }
+
+        for rule in rules.iter().filter(|r| r.kind != BUY_GET && r.species < 0) {
+            // bill rules are checked against when the table sat down
+            if !rule.applies_at(opened) {
+                continue;
+            }
+
+            let left = lines.iter().map(net).collect::<Vec<i64>>();
+            let total = left.iter().sum::<i64>();
+            let amount = match rule.kind {
+                PERCENT => apply_rate(total, rule.value as i32),
+                _ => rule.value.min(total)
+            };
+            if amount <= 0 {
+                continue;
+            }
+
+            // spread over the lines so tax is still worked out on what was actually charged
+            for (i, part) in allocate(amount, &left, 0).into_iter().enumerate() {
+                lines[i].discount += part;
+            }
+            applied.push(BillDiscount { rule: rule.id, name: rule.name.clone(), request: None, amount });
+        }
+
+        applied
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/<name>/<kind>/<value>?<species>&<buy>&<get>&<code>&<starts>&<ends>&<from>&<until>&<limit>")]
+pub async fn create(db: &State<Pool<Sqlite>>, name: String, kind: i32, value: i64, species: Option<i32>, buy: Option<i32>, get: Option<i32>, code: Option<String>, starts: Option<i32>, ends: Option<i32>, from: Option<String>, until: Option<String>, limit: Option<i32>) -> String {
+    // from, until -> "17:00", "19:00"
+    let from_minute = from.map(|x| parse_minute(&x));
+    let until_minute = until.map(|x| parse_minute(&x));
+    if from_minute.is_some_and(|x| x.is_none()) || until_minute.is_some_and(|x| x.is_none()) {
+        return Result::RuleInvalid.to_string();
+    }
+
+    Discount::create(db.inner(), name, kind, value, species.unwrap_or(-1), buy.unwrap_or(0), get.unwrap_or(0), code, starts, ends, from_minute.flatten(), until_minute.flatten(), limit).await.to_string()
+}
+
+#[get("/<id>")]
+pub async fn enable(db: &State<Pool<Sqlite>>, id: i32) -> String {
+    Discount::set_active(db.inner(), id, true).await.to_string()
+}
+
+#[get("/<id>")]
+pub async fn disable(db: &State<Pool<Sqlite>>, id: i32) -> String {
+    Discount::set_active(db.inner(), id, false).await.to_string()
+}
+
+#[get("/")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Discount::fetch_all(db.inner()).await).unwrap()
+}
+
+#[post("/<code>", data="<table>")]
+pub async fn redeem(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, table: String, code: String) -> String {
+    let db = db.inner();
+    match Desk::fetch(db, &table).await {
+        Some(d) => Discount::redeem(db, config.inner(), &d.name, &code).await.to_string(),
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(request: i32, species: i32, quantity: i32, unit_price: i64) -> BillLine {
+        BillLine {
+            request,
+            dish: 1,
+            name: String::new(),
+            size: String::new(),
+            options: vec![],
+            quantity,
+            unit_price,
+            amount: unit_price * quantity as i64,
+            discount: 0,
+            species,
+            tax_category: 0
+        }
+    }
+
+    fn rule(id: i32, kind: i32, value: i64, species: i32) -> PricingRule {
+        PricingRule {
+            id,
+            name: format!("rule {id}"),
+            kind,
+            value,
+            species,
+            buy: 0,
+            get: 0,
+            code: None,
+            starts: None,
+            ends: None,
+            from_minute: None,
+            until_minute: None,
+            usage_limit: None,
+            active: true,
+            created: 0
+        }
+    }
+
+    fn local(hour: i32, minute: i32) -> i32 {
+        // unix time on the first day, at this local time
+        (hour * 3600 + minute * 60 - utc_offset()).rem_euclid(86400)
+    }
+
+    #[test]
+    fn percent_off_one_species() {
+        let mut lines = vec![line(1, 1, 2, 1000), line(2, 2, 1, 500)];
+        let applied = Discount::apply_rules(&[rule(1, PERCENT, 1000, 1)], 0, &[0, 0], &mut lines);
+
+        assert_eq!(lines[0].discount, 200);
+        assert_eq!(lines[1].discount, 0);
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].request, Some(1));
+    }
+
+    #[test]
+    fn fixed_bill_rule_is_spread_and_capped() {
+        let mut lines = vec![line(1, 1, 1, 300), line(2, 1, 1, 100)];
+        let applied = Discount::apply_rules(&[rule(1, FIXED, 1000, -1)], 0, &[0, 0], &mut lines);
+
+        // never more than the bill itself
+        assert_eq!(applied[0].amount, 400);
+        assert_eq!(lines.iter().map(|l| l.discount).sum::<i64>(), 400);
+        assert!(lines.iter().all(|l| l.discount <= l.amount));
+    }
+
+    #[test]
+    fn buy_two_get_one_frees_the_cheapest() {
+        let mut r = rule(1, BUY_GET, 0, 1);
+        (r.buy, r.get) = (2, 1);
+        let mut lines = vec![line(1, 1, 1, 1200), line(2, 1, 1, 800), line(3, 1, 2, 1000)];
+        Discount::apply_rules(&[r], 0, &[0, 0, 0], &mut lines);
+
+        // 1200, 1000, 1000 -> the 1000 goes free, 800 is left without a group
+        assert_eq!(lines.iter().map(|l| l.discount).collect::<Vec<i64>>(), vec![0, 0, 1000]);
+    }
+
+    #[test]
+    fn line_rules_go_before_bill_rules() {
+        let mut lines = vec![line(1, 1, 1, 1000), line(2, 2, 1, 1000)];
+        let applied = Discount::apply_rules(&[rule(2, PERCENT, 1000, -1), rule(1, PERCENT, 5000, 1)], 0, &[0, 0], &mut lines);
+
+        // half off the first line, then 10% off the 1500 left
+        assert_eq!(applied.iter().map(|d| (d.rule, d.amount)).collect::<Vec<(i32, i64)>>(), vec![(1, 500), (2, 150)]);
+        assert_eq!(lines.iter().map(|l| l.discount).sum::<i64>(), 650);
+    }
+
+    #[test]
+    fn happy_hour_counts_when_the_line_was_ordered() {
+        let mut r = rule(1, PERCENT, 5000, 1);
+        (r.from_minute, r.until_minute) = (Some(17 * 60), Some(19 * 60));
+        let mut lines = vec![line(1, 1, 1, 1000), line(2, 1, 1, 1000)];
+        Discount::apply_rules(&[r], 0, &[local(17, 30), local(19, 0)], &mut lines);
+
+        assert_eq!(lines[0].discount, 500);
+        assert_eq!(lines[1].discount, 0);
+    }
+
+    #[test]
+    fn dates_bound_the_rule() {
+        let mut r = rule(1, FIXED, 100, -1);
+        (r.starts, r.ends) = (Some(1000), Some(2000));
+        for (opened, expected) in [(999, 0), (1000, 100), (2000, 100), (2001, 0)] {
+            let mut lines = vec![line(1, 1, 1, 1000)];
+            Discount::apply_rules(std::slice::from_ref(&r), opened, &[opened], &mut lines);
+            assert_eq!(lines[0].discount, expected, "opened at {opened}");
+        }
+    }
+}
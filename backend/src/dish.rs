@@ -1,8 +1,8 @@
 use rocket::State;
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, Pool, Sqlite};
+use sqlx::{prelude::FromRow, Executor, Pool, Sqlite};
 
-use crate::{callback_result::Result, utils::decode_uri};
+use crate::{callback_result::Result, picture::{Picture, PictureUrls}, tag::{OptionTag, Tag}, translation::{Locale, Translation}, utils::decode_uri};
 
 #[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
 pub struct Dish {
@@ -23,6 +23,23 @@ pub struct Dish {
     pub sizes: String,
     // Vec<String>
     pub species: i32,
+    pub station: i32,
+    // -1 -> goes wherever its species goes
+    pub course: i32,
+    // -1 -> served with its species' course
+    pub prep_time: i32,
+    // seconds
+    pub prep_sizes: String,
+    // Vec<Option<i32>>, one per size overriding prep_time, "[]" when none are set
+    pub prices: String,
+    // Vec<i64>, one per size in minor units (sen), "[]" until priced
+
+    #[sqlx(skip)]
+    pub tags: Vec<Tag>,
+    #[sqlx(skip)]
+    pub option_tags: Vec<OptionTag>,
+    #[sqlx(skip)]
+    pub picture: Option<PictureUrls>,
 }
 impl Dish {
     pub async fn create(db: &Pool<Sqlite>, name: String, variants: String, sizes: String, species: i32) -> Result {        
@@ -59,9 +76,118 @@ impl Dish {
         }
     }
 
+    pub async fn set_prep_time(db: &Pool<Sqlite>, id: i32, seconds: i32, size: Option<i32>) -> Result {
+        // size None -> the dish's own prep time
+        // size Some -> override for that size, seconds -1 clears it
+        let dish = match Dish::fetch(db, id).await {
+            Some(d) => d,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+
+        match size {
+            None => {
+                if seconds < 0 {
+                    return Result::PrepTimeInvalid;
+                }
+
+                sqlx::query("update dish set prep_time = $1 where id = $2;")
+                    .bind(seconds)
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+            },
+            Some(size) => {
+                let count = dish.sizes.split(',').count();
+                if size < 0 || size as usize >= count {
+                    return Result::SizeDoesntExist;
+                }
+                if seconds < -1 {
+                    return Result::PrepTimeInvalid;
+                }
+
+                let mut overrides = serde_json::from_str::<Vec<Option<i32>>>(&dish.prep_sizes).unwrap_or_default();
+                overrides.resize(count, None);
+                overrides[size as usize] = if seconds == -1 { None } else { Some(seconds) };
+
+                sqlx::query("update dish set prep_sizes = $1 where id = $2;")
+                    .bind(serde_json::to_string(&overrides).unwrap())
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        Result::Success
+    }
+
+    pub async fn set_prices(db: &Pool<Sqlite>, id: i32, prices: &str) -> Result {
+        // "1200,1500" -> one price per size, in minor units
+        let dish = match Dish::fetch(db, id).await {
+            Some(d) => d,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+
+        let prices = match prices.split(',').map(|x| x.trim().parse::<i64>()).collect::<std::result::Result<Vec<i64>, _>>() {
+            Ok(p) => p,
+            Err(_) => {
+                return Result::PriceInvalid;
+            }
+        };
+        if prices.len() != dish.sizes.split(',').count() || prices.iter().any(|p| *p < 0) {
+            return Result::PriceInvalid;
+        }
+
+        sqlx::query("update dish set prices = $1 where id = $2;")
+            .bind(serde_json::to_string(&prices).unwrap())
+            .bind(id)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub fn price(&self, size: i32) -> Option<i64> {
+        // None -> the size hasnt been priced yet, it cant be ordered until it is
+        serde_json::from_str::<Vec<i64>>(&self.prices)
+            .unwrap_or_default()
+            .get(size as usize)
+            .copied()
+    }
+
+    pub fn planned_prep_time(&self, size: i32) -> i32 {
+        serde_json::from_str::<Vec<Option<i32>>>(&self.prep_sizes)
+            .unwrap_or_default()
+            .get(size as usize)
+            .copied()
+            .flatten()
+            .unwrap_or(self.prep_time)
+    }
+
     pub async fn delete(db: &Pool<Sqlite>, id: i32) -> Result {
         match Dish::fetch(db, id).await {
             Some(_) => {
+                sqlx::query("delete from dish_tag where dish = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                sqlx::query("delete from option_tag where dish = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Translation::delete_dish(db, id).await;
+                Picture::delete(db, 1, id).await;
+
                 sqlx::query("delete from dish where id = $1;")
                     .bind(id)
                     .execute(db)
@@ -74,11 +200,11 @@ impl Dish {
     }
 
     pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Dish> {
-        match sqlx::query_as("select * from dish where id = $1;")
+        match sqlx::query_as::<_, Dish>("select * from dish where id = $1;")
             .bind(id)
             .fetch_one(db)
             .await {
-            Ok(d) => Some(d),
+            Ok(d) => Some(Dish::attach_extras(db, d).await),
             Err(e) => {
                 println!("dish.rs; fetch({id}); error : {e}");
                 None
@@ -86,11 +212,45 @@ impl Dish {
         }
     }
 
+    pub async fn fetch_row<'e, E: Executor<'e, Database = Sqlite>>(db: E, id: i32) -> Option<Dish> {
+        // just the dish, without tags or picture, for checks made inside a transaction
+        sqlx::query_as("select * from dish where id = $1;")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
     pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Dish> {
-        sqlx::query_as("select * from dish;")
+        let dishes = sqlx::query_as::<_, Dish>("select * from dish;")
             .fetch_all(db)
             .await
-            .unwrap()
+            .unwrap();
+
+        let mut result = vec![];
+        for d in dishes {
+            result.push(Dish::attach_extras(db, d).await);
+        }
+        result
+    }
+
+    pub async fn fetch_filtered(db: &Pool<Sqlite>, exclude: Vec<String>, require: Vec<String>) -> Vec<Dish> {
+        // exclude -> drop dishes carrying any of these tags ("nuts")
+        // require -> keep only dishes carrying all of these tags ("vegan")
+        Dish::fetch_all(db).await
+            .into_iter()
+            .filter(|d| {
+                let names = d.tags.iter().map(|t| t.name.to_lowercase()).collect::<Vec<String>>();
+                !exclude.iter().any(|e| names.contains(e)) && require.iter().all(|r| names.contains(r))
+            })
+            .collect()
+    }
+
+    async fn attach_extras(db: &Pool<Sqlite>, mut dish: Dish) -> Dish {
+        dish.tags = Tag::fetch_for_dish(db, dish.id).await;
+        dish.option_tags = Tag::fetch_options_for_dish(db, dish.id).await;
+        dish.picture = Picture::urls(db, 1, dish.id).await;
+        dish
     }
 }
 
@@ -104,17 +264,41 @@ pub async fn edit(db: &State<Pool<Sqlite>>, id: i32, name: String, variants: Str
     Dish::edit(db.inner(), id, decode_uri(name), decode_uri(variants), decode_uri(sizes), species).await.to_string()
 }
 
+#[get("/<id>/<seconds>?<size>")]
+pub async fn prep_time(db: &State<Pool<Sqlite>>, id: i32, seconds: i32, size: Option<i32>) -> String {
+    Dish::set_prep_time(db.inner(), id, seconds, size).await.to_string()
+}
+
+#[get("/<id>/<prices>")]
+pub async fn prices(db: &State<Pool<Sqlite>>, id: i32, prices: String) -> String {
+    Dish::set_prices(db.inner(), id, &decode_uri(prices)).await.to_string()
+}
+
 #[get("/<id>")]
 pub async fn delete(db: &State<Pool<Sqlite>>, id: i32) -> String {
     Dish::delete(db.inner(), id).await.to_string()
 }
 
 #[get("/<id>")]
-pub async fn fetch(db: &State<Pool<Sqlite>>, id: i32) -> String {
-    serde_json::to_string(&Dish::fetch(db.inner(), id).await).unwrap()
+pub async fn fetch(db: &State<Pool<Sqlite>>, id: i32, locale: Locale) -> String {
+    let db = db.inner();
+    let result = match Dish::fetch(db, id).await {
+        Some(d) => Some(Translation::localise_dish(db, d, &locale.0).await),
+        None => None
+    };
+    serde_json::to_string(&result).unwrap()
 }
 
-#[get("/")]
-pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
-    serde_json::to_string(&Dish::fetch_all(db.inner()).await).unwrap()
+#[get("/?<exclude>&<require>")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>, exclude: Option<String>, require: Option<String>, locale: Locale) -> String {
+    let db = db.inner();
+    let mut result = vec![];
+    for d in Dish::fetch_filtered(
+        db,
+        Tag::parse_names(&exclude.unwrap_or_default()),
+        Tag::parse_names(&require.unwrap_or_default())
+    ).await {
+        result.push(Translation::localise_dish(db, d, &locale.0).await);
+    }
+    serde_json::to_string(&result).unwrap()
 }
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::{dish::Dish, request::{Request, COMPLETED, IN_KITCHEN, PENDING}, station::{Station, STATION_OF}, utils::{get_time, ValueInt}};
+
+pub const CAPACITY: i32 = 3;
+// lines a station works on at once, spreads the queue ahead of a request
+pub const SAMPLES: i32 = 20;
+// how many recent cooks the actual prep time is taken from
+pub const MIN_SAMPLES: usize = 5;
+// below this the planned prep time is trusted over the measured one
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrepTime {
+    pub size: i32,
+    pub planned: i32,
+    pub actual: Option<i32>,
+    // median seconds from in kitchen to completed
+    pub samples: i32
+}
+
+pub struct Eta;
+impl Eta {
+    async fn measured(db: &Pool<Sqlite>, dish: i32, size: i32) -> Vec<i32> {
+        let mut times = sqlx::query_as::<_, ValueInt>("select c.time - k.time from request r join request_state k on k.request = r.id and k.state = $3 join request_state c on c.request = r.id and c.state = $4 where r.dish = $1 and r.size = $2 and c.time >= k.time order by c.id desc limit $5;")
+            .bind(dish)
+            .bind(size)
+            .bind(IN_KITCHEN)
+            .bind(COMPLETED)
+            .bind(SAMPLES)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0 as i32)
+            .collect::<Vec<i32>>();
+        times.sort();
+        times
+    }
+
+    pub async fn prep_times(db: &Pool<Sqlite>, dish: i32) -> Option<Vec<PrepTime>> {
+        let dish = Dish::fetch(db, dish).await?;
+
+        let mut result = vec![];
+        for size in 0..dish.sizes.split(',').count() as i32 {
+            let times = Eta::measured(db, dish.id, size).await;
+            result.push(PrepTime {
+                size,
+                planned: dish.planned_prep_time(size),
+                actual: times.get(times.len() / 2).copied(),
+                samples: times.len() as i32
+            });
+        }
+        Some(result)
+    }
+
+    pub async fn prep_time(db: &Pool<Sqlite>, dish: i32, size: i32) -> i32 {
+        let times = Eta::measured(db, dish, size).await;
+        if times.len() >= MIN_SAMPLES {
+            return times[times.len() / 2];
+        }
+
+        match Dish::fetch(db, dish).await {
+            Some(d) => d.planned_prep_time(size),
+            None => 0
+        }
+    }
+
+    async fn started(db: &Pool<Sqlite>, request_id: i32) -> Option<i32> {
+        sqlx::query_as::<_, ValueInt>("select time from request_state where request = $1 and state = $2 order by id desc limit 1;")
+            .bind(request_id)
+            .bind(IN_KITCHEN)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .map(|x| x.0 as i32)
+    }
+
+    pub async fn of(db: &Pool<Sqlite>, request: &Request) -> Option<i32> {
+        // unix time the request should be ready, None once its done or while its course is held
+        if request.held || request.state >= COMPLETED {
+            return None;
+        }
+
+        let now = get_time();
+        let prep = Eta::prep_time(db, request.dish, request.size).await;
+
+        if request.state == IN_KITCHEN {
+            let started = Eta::started(db, request.id).await.unwrap_or(now);
+            return Some((started + prep).max(now));
+        }
+
+        // everything the station has to get through before this one
+        let station = Station::of_request(db, request.id).await;
+        let ahead = sqlx::query_as::<_, Request>(&format!(
+            "select request.* from request join dish on dish.id = request.dish left join species on species.id = dish.species where (request.state = $1 or request.state = $2) and request.held = 0 and {STATION_OF} = $3 and (request.created < $4 or (request.created = $4 and request.id < $5));"
+        ))
+            .bind(PENDING)
+            .bind(IN_KITCHEN)
+            .bind(station)
+            .bind(request.created)
+            .bind(request.id)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut preps: HashMap<(i32, i32), i32> = HashMap::new();
+        let mut work = 0;
+        for r in ahead {
+            let p = match preps.get(&(r.dish, r.size)) {
+                Some(p) => *p,
+                None => {
+                    let p = Eta::prep_time(db, r.dish, r.size).await;
+                    preps.insert((r.dish, r.size), p);
+                    p
+                }
+            };
+
+            work += if r.state == IN_KITCHEN {
+                (Eta::started(db, r.id).await.unwrap_or(now) + p - now).max(0)
+            } else {
+                p
+            };
+        }
+
+        Some(now + work / CAPACITY + prep)
+    }
+
+    pub async fn attach(db: &Pool<Sqlite>, mut request: Request) -> Request {
+        request.eta = Eta::of(db, &request).await;
+        request
+    }
+}
+
+#[get("/<dish>")]
+pub async fn prep_times(db: &State<Pool<Sqlite>>, dish: i32) -> String {
+    serde_json::to_string(&Eta::prep_times(db.inner(), dish).await).unwrap()
+}
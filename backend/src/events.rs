@@ -0,0 +1,185 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use rocket::{request::{FromRequest, Outcome}, response::stream::{Event, EventStream}, tokio::{select, sync::broadcast::{self, error::RecvError}}, Shutdown, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::{callback_result::Result, desk::Desk, eta::Eta, request::Request, staff, station::Station, utils::{decode_uri, get_time}, validation::Validation};
+
+pub const CREATED: &str = "created";
+pub const STATE_CHANGED: &str = "state_changed";
+pub const CANCELLED: &str = "cancelled";
+pub const FIRED: &str = "fired";
+pub const HELD: &str = "held";
+pub const PRIORITY_CHANGED: &str = "priority_changed";
+pub const OVERDUE: &str = "overdue";
+// staff only, tables never hear about these
+
+const RECENT: usize = 1000;
+// how many events are kept around for clients reconnecting with Last-Event-ID
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEvent {
+    pub id: u64,
+    pub kind: String,
+    pub request: i32,
+    pub desk: String,
+    pub state: i32,
+    pub station: i32,
+    pub held: bool,
+    pub eta: Option<i32>,
+    pub time: i32
+}
+
+impl RequestEvent {
+    fn to_sse(&self) -> Event {
+        Event::data(serde_json::to_string(self).unwrap())
+            .id(self.id.to_string())
+            .event(self.kind.clone())
+    }
+}
+
+pub enum Scope {
+    Table(String),
+    Station(Option<i32>)
+    // None -> every station
+}
+impl Scope {
+    fn allows(&self, e: &RequestEvent) -> bool {
+        match self {
+            Scope::Table(t) => e.desk == *t && e.kind != OVERDUE,
+            // the kitchen hears about held lines once theyre fired
+            Scope::Station(s) => s.is_none_or(|s| s == e.station) && !(e.held && e.kind == CREATED)
+        }
+    }
+}
+
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        // browsers send this by themselves when an EventSource reconnects
+        Outcome::Success(LastEventId(
+            request.headers().get_one("Last-Event-ID").and_then(|x| x.trim().parse().ok())
+        ))
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    // cloned into background tasks, every clone shares the same channel
+    sender: broadcast::Sender<RequestEvent>,
+    recent: Arc<Mutex<(u64, VecDeque<RequestEvent>)>>
+    // (last id handed out, newest events)
+}
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            sender: broadcast::channel(256).0,
+            recent: Arc::new(Mutex::new((0, VecDeque::new())))
+        }
+    }
+
+    pub fn publish(&self, kind: &str, request: &Request, station: i32) {
+        // the lock is held over send so ids reach subscribers in order
+        let mut recent = self.recent.lock().unwrap();
+        recent.0 += 1;
+
+        let event = RequestEvent {
+            id: recent.0,
+            kind: kind.to_string(),
+            request: request.id,
+            desk: request.desk.clone(),
+            state: request.state,
+            station,
+            held: request.held,
+            eta: request.eta,
+            time: get_time()
+        };
+
+        recent.1.push_back(event.clone());
+        if recent.1.len() > RECENT {
+            recent.1.pop_front();
+        }
+
+        // no subscribers isnt an error
+        let _ = self.sender.send(event);
+    }
+
+    pub async fn publish_id(&self, db: &Pool<Sqlite>, kind: &str, request_id: i32) {
+        if let Some(r) = Request::fetch(db, request_id).await {
+            let r = Eta::attach(db, r).await;
+            self.publish(kind, &r, Station::of_request(db, request_id).await);
+        }
+    }
+
+    pub fn since(&self, last: u64, scope: &Scope) -> Vec<RequestEvent> {
+        self.recent.lock().unwrap().1.iter()
+            .filter(|e| e.id > last && scope.allows(e))
+            .cloned()
+            .collect()
+    }
+
+    pub fn stream(&self, scope: Scope, last: LastEventId, mut end: Shutdown) -> EventStream![] {
+        // subscribe before reading the backlog so nothing falls in between
+        let mut receiver = self.sender.subscribe();
+
+        // ids start over when the server restarts, an id from before that means replay everything kept
+        let last = last.0.map(|l| if l > self.recent.lock().unwrap().0 { 0 } else { l });
+        let backlog = match last {
+            Some(l) => self.since(l, &scope),
+            None => vec![]
+        };
+
+        let bus = self.clone();
+        EventStream! {
+            let mut sent = last.unwrap_or(0);
+            for e in backlog {
+                sent = e.id;
+                yield e.to_sse();
+            }
+
+            loop {
+                let events = select! {
+                    message = receiver.recv() => match message {
+                        Ok(e) => vec![e],
+                        Err(RecvError::Closed) => break,
+                        // the channel dropped some, fill the gap from what recent still holds
+                        Err(RecvError::Lagged(_)) => bus.since(sent, &scope)
+                    },
+                    _ = &mut end => break
+                };
+
+                for e in events {
+                    if e.id <= sent || !scope.allows(&e) {
+                        continue;
+                    }
+                    sent = e.id;
+                    yield e.to_sse();
+                }
+            }
+        }
+    }
+}
+
+#[get("/<table>")]
+pub async fn table(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, table: String, last: LastEventId, end: Shutdown) -> std::result::Result<EventStream![], String> {
+    // EventSource can only GET, so the table name goes in the path instead of the body
+    match Desk::fetch(db.inner(), &decode_uri(table)).await {
+        Some(d) => Ok(bus.stream(Scope::Table(d.name), last, end)),
+        None => Err(Result::NoTable.to_string())
+    }
+}
+
+#[post("/?<station>", data="<login>")]
+pub async fn kitchen(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, station: Option<i32>, last: LastEventId, end: Shutdown) -> std::result::Result<EventStream![], String> {
+    // the login goes in the body like every other staff route, so it never shows up in access logs
+    // a plain EventSource cant post, kitchen screens read the stream with fetch instead
+    if !Validation::verify_staff(db.inner(), &login, &staff::ROLES).await {
+        return Err(Result::NoPermission.to_string());
+    }
+    Ok(bus.stream(Scope::Station(station), last, end))
+}
@@ -0,0 +1,298 @@
+use rand::Rng;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, desk::Desk, staff, utils::{decode_uri, generate_name, get_time, ValueInt}, validation::Validation};
+
+pub const SUFFIX: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+// no 0/o or 1/i/l, read off a card these get mixed up
+pub const SUFFIX_LENGTH: usize = 8;
+// 31^8, about 40 bits, the words are only there to make it easier to type
+
+pub const MAX_ATTEMPTS: i64 = 5;
+pub const LOCKOUT: i32 = 600;
+// a table that gets MAX_ATTEMPTS codes wrong in LOCKOUT seconds cant look any more up until they age out
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct GiftCard {
+    pub code: String,
+    // adjective + noun + random suffix + two check digits, "ancientconstellation-k7pw3xqe-42"
+    pub initial: i64,
+    pub balance: i64,
+    pub issued: i32,
+    pub expires: Option<i32>
+    // None -> never
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct GiftCardEntry {
+    pub id: i32,
+    pub code: String,
+    pub amount: i64,
+    // + issued or refunded onto the card, - spent
+    pub session: Option<i32>,
+    pub time: i32
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Liability {
+    pub cards: i64,
+    pub outstanding: i64,
+    // still owed to guests on cards that can be spent
+    pub expired: i64
+    // left on expired cards, no longer owed
+}
+
+pub fn checksum(name: &str) -> u32 {
+    // weighted by position so swapped letters are caught too
+    // only catches typos, it isnt secret, the suffix is what makes codes hard to guess
+    name.bytes().enumerate().map(|(i, b)| (i as u32 + 1) * b as u32).sum::<u32>() % 97
+}
+
+pub fn normalize(code: &str) -> Option<String> {
+    // whatever the guest typed -> the stored code, None if the check digits dont match
+    let code = code.trim().to_lowercase().replace(' ', "");
+    let (name, check) = code.rsplit_once('-')?;
+    if check.parse::<u32>().ok()? != checksum(name) {
+        return None;
+    }
+    Some(code)
+}
+
+impl GiftCard {
+    // CREATE TABLE gift_card(code varchar primary key, initial int, balance int, issued int, expires int);
+    // CREATE TABLE gift_card_entry(id integer primary key autoincrement, code varchar, amount int, session int, time int);
+    // CREATE TABLE gift_card_attempt(desk varchar, time int);
+    // failed lookups from tables
+
+    pub async fn issue(db: &Pool<Sqlite>, amount: i64, expires: Option<i32>) -> std::result::Result<GiftCard, Result> {
+        if amount <= 0 || expires.is_some_and(|e| e <= get_time()) {
+            return Err(Result::GiftCardInvalid);
+        }
+
+        loop {
+            let name = {
+                let mut rng = rand::thread_rng();
+                let words = generate_name(&mut rng).to_lowercase().replace(' ', "");
+                let suffix = (0..SUFFIX_LENGTH).map(|_| SUFFIX[rng.gen_range(0..SUFFIX.len())] as char).collect::<String>();
+                format!("{words}-{suffix}")
+            };
+            let code = format!("{name}-{:02}", checksum(&name));
+
+            let inserted = sqlx::query("insert or ignore into gift_card(code, initial, balance, issued, expires) values($1, $2, $2, $3, $4);")
+                .bind(&code)
+                .bind(amount)
+                .bind(get_time())
+                .bind(expires)
+                .execute(db)
+                .await
+                .unwrap()
+                .rows_affected();
+
+            // taken already, pick another name
+            if inserted == 1 {
+                GiftCard::entry(db, &code, amount, None).await;
+                return Ok(GiftCard::fetch(db, &code).await.unwrap());
+            }
+        }
+    }
+
+    async fn entry(db: &Pool<Sqlite>, code: &str, amount: i64, session: Option<i32>) {
+        sqlx::query("insert into gift_card_entry(code, amount, session, time) values($1, $2, $3, $4);")
+            .bind(code)
+            .bind(amount)
+            .bind(session)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, code: &str) -> Option<GiftCard> {
+        match sqlx::query_as("select * from gift_card where code = $1;")
+            .bind(normalize(code)?)
+            .fetch_one(db)
+            .await {
+            Ok(g) => Some(g),
+            Err(e) => {
+                println!("gift_card.rs; fetch({code}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn usable(db: &Pool<Sqlite>, code: &str) -> Option<GiftCard> {
+        GiftCard::fetch(db, code).await.filter(|g| g.expires.is_none_or(|e| e > get_time()))
+    }
+
+    pub async fn locked(db: &Pool<Sqlite>, desk: &str) -> bool {
+        sqlx::query_as::<_, ValueInt>("select count(*) from gift_card_attempt where desk = $1 and time > $2;")
+            .bind(desk)
+            .bind(get_time() - LOCKOUT)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0 >= MAX_ATTEMPTS
+    }
+
+    pub async fn lookup(db: &Pool<Sqlite>, desk: &str, code: &str) -> std::result::Result<GiftCard, Result> {
+        // for tables, where every wrong code counts towards the lockout
+        if GiftCard::locked(db, desk).await {
+            return Err(Result::GiftCardLocked);
+        }
+        if let Some(g) = GiftCard::usable(db, code).await {
+            return Ok(g);
+        }
+
+        sqlx::query("insert into gift_card_attempt(desk, time) values($1, $2);")
+            .bind(desk)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap();
+        Err(Result::GiftCardInvalid)
+    }
+
+    pub async fn charge(db: &Pool<Sqlite>, code: &str, amount: i64, session: i32) -> std::result::Result<String, String> {
+        // same shape as PaymentProvider::charge, Ok -> the code as the payment's reference
+        let card = GiftCard::usable(db, code).await.ok_or("unknown or expired gift card")?;
+
+        // checked and taken in one statement so two payments cant spend the same balance
+        let taken = sqlx::query("update gift_card set balance = balance - $1 where code = $2 and balance >= $1;")
+            .bind(amount)
+            .bind(&card.code)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+        if taken == 0 {
+            return Err(format!("gift card balance {} too low", card.balance));
+        }
+
+        GiftCard::entry(db, &card.code, -amount, Some(session)).await;
+        Ok(card.code)
+    }
+
+    pub async fn credit(db: &Pool<Sqlite>, code: &str, amount: i64, session: i32) -> std::result::Result<String, String> {
+        // refunds go back onto the card, even an expired one
+        let card = GiftCard::fetch(db, code).await.ok_or("unknown gift card")?;
+
+        sqlx::query("update gift_card set balance = balance + $1 where code = $2;")
+            .bind(amount)
+            .bind(&card.code)
+            .execute(db)
+            .await
+            .unwrap();
+
+        GiftCard::entry(db, &card.code, amount, Some(session)).await;
+        Ok(card.code)
+    }
+
+    pub async fn expire(db: &Pool<Sqlite>, code: &str) -> Result {
+        let card = match GiftCard::usable(db, code).await {
+            Some(g) => g,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+
+        sqlx::query("update gift_card set expires = $1 where code = $2;")
+            .bind(get_time())
+            .bind(card.code)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn history(db: &Pool<Sqlite>, code: &str) -> Vec<GiftCardEntry> {
+        sqlx::query_as("select * from gift_card_entry where code = $1 order by id;")
+            .bind(normalize(code).unwrap_or_default())
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn liability(db: &Pool<Sqlite>) -> Liability {
+        sqlx::query_as("select count(*) as cards, coalesce(sum(case when expires is null or expires > $1 then balance else 0 end), 0) as outstanding, coalesce(sum(case when expires is null or expires > $1 then 0 else balance end), 0) as expired from gift_card;")
+            .bind(get_time())
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[post("/<amount>?<expires>", data="<login>")]
+pub async fn issue(db: &State<Pool<Sqlite>>, login: String, amount: i64, expires: Option<i32>) -> String {
+    let db = db.inner();
+    if Validation::verified_login(db, &login, &[staff::MANAGER]).await.is_none() {
+        return Result::NoPermission.to_string();
+    }
+    match GiftCard::issue(db, amount, expires).await {
+        Ok(g) => serde_json::to_string(&g).unwrap(),
+        Err(e) => e.to_string()
+    }
+}
+
+#[get("/<code>")]
+pub async fn fetch(db: &State<Pool<Sqlite>>, code: String) -> String {
+    serde_json::to_string(&GiftCard::fetch(db.inner(), &decode_uri(code)).await).unwrap()
+}
+
+#[post("/<code>", data="<login>")]
+pub async fn expire(db: &State<Pool<Sqlite>>, login: String, code: String) -> String {
+    let db = db.inner();
+    if Validation::verified_login(db, &login, &[staff::MANAGER]).await.is_none() {
+        return Result::NoPermission.to_string();
+    }
+    GiftCard::expire(db, &decode_uri(code)).await.to_string()
+}
+
+#[get("/<code>")]
+pub async fn history(db: &State<Pool<Sqlite>>, code: String) -> String {
+    serde_json::to_string(&GiftCard::history(db.inner(), &decode_uri(code)).await).unwrap()
+}
+
+#[get("/")]
+pub async fn liability(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&GiftCard::liability(db.inner()).await).unwrap()
+}
+
+#[post("/<code>", data="<table>")]
+pub async fn balance(db: &State<Pool<Sqlite>>, table: String, code: String) -> String {
+    // guests checking what is left before paying with it
+    let db = db.inner();
+    let desk = match Desk::fetch(db, &table).await {
+        Some(d) => d,
+        None => {
+            return Result::NoTable.to_string();
+        }
+    };
+    match GiftCard::lookup(db, &desk.name, &decode_uri(code)).await {
+        Ok(g) => serde_json::to_string(&g).unwrap(),
+        Err(e) => e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_catches_typos() {
+        let name = "ancientconstellation-k7pw3xqe";
+        let code = format!("{name}-{:02}", checksum(name));
+        assert_eq!(normalize(&code), Some(code.clone()));
+        // what guests actually type
+        assert_eq!(normalize(&format!(" {} ", code.to_uppercase())), Some(code.clone()));
+        assert_eq!(normalize(&code.replace("constellation", "constel lation")), Some(code.clone()));
+
+        // one letter off, two swapped, wrong check digits
+        assert_eq!(normalize(&code.replace("k7pw", "k7px")), None);
+        assert_eq!(normalize(&code.replace("k7pw", "7kpw")), None);
+        assert_eq!(normalize(&format!("{name}-{:02}", (checksum(name) + 1) % 97)), None);
+        assert_eq!(normalize(name), None);
+    }
+}
@@ -0,0 +1,95 @@
+use rocket::request::{FromRequest, Outcome};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, utils::get_time};
+
+pub const DEFAULT_WINDOW: i32 = 24 * 60 * 60;
+// seconds, override with ROCKET_IDEMPOTENCY_WINDOW or idempotency_window in Rocket.toml
+
+pub const IN_PROGRESS_TIMEOUT: i32 = 60;
+// seconds, a key still without a response after this is taken to be from an attempt that died
+// (a panicking handler never gets to finish()) and can be tried again
+
+pub struct IdempotencyConfig {
+    pub window: i32
+}
+
+pub struct IdempotencyKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IdempotencyKey(
+            request.headers().get_one("Idempotency-Key")
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+        ))
+    }
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Idempotency {
+    pub key: String,
+    pub desk: String,
+    pub ids: String,
+    // Vec<i32>, the requests this key created
+    pub response: Option<String>,
+    // null while the first attempt is still running
+    pub created: i32
+}
+impl Idempotency {
+    // CREATE TABLE idempotency(key varchar, desk varchar, ids varchar, response varchar, created int, primary key(key, desk));
+
+    pub async fn begin(db: &Pool<Sqlite>, key: &str, desk: &str, window: i32) -> Option<String> {
+        // Some(response) -> this is a retry, send that back and do nothing else
+        // None -> first time this key is seen, go ahead then call finish()
+        sqlx::query("delete from idempotency where created < $1 or (response is null and created < $2);")
+            .bind(get_time() - window)
+            .bind(get_time() - IN_PROGRESS_TIMEOUT)
+            .execute(db)
+            .await
+            .unwrap();
+
+        // inserting first means two retries racing each other cant both get through
+        let reserved = sqlx::query("insert or ignore into idempotency(key, desk, ids, response, created) values($1, $2, '[]', null, $3);")
+            .bind(key)
+            .bind(desk)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected() > 0;
+
+        if reserved {
+            return None;
+        }
+
+        match Idempotency::fetch(db, key, desk).await {
+            Some(Idempotency { response: Some(r), .. }) => Some(r),
+            _ => Some(Result::IdempotencyInProgress.to_string())
+        }
+    }
+
+    pub async fn finish(db: &Pool<Sqlite>, key: &str, desk: &str, ids: &[i32], response: &str) {
+        sqlx::query("update idempotency set ids = $1, response = $2 where key = $3 and desk = $4;")
+            .bind(serde_json::to_string(ids).unwrap())
+            .bind(response)
+            .bind(key)
+            .bind(desk)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, key: &str, desk: &str) -> Option<Idempotency> {
+        sqlx::query_as("select * from idempotency where key = $1 and desk = $2;")
+            .bind(key)
+            .bind(desk)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+}
@@ -0,0 +1,313 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{allergy::Allergy, callback_result::Result, desk::Desk, events::{self, EventBus}, request::{Request, RequestState, CANCELLED, COMPLETED, IN_KITCHEN, PENDING}, staff, station::STATION_OF, utils::decode_uri, validation::Validation};
+
+const FROM_ROUTED: &str = "from request join dish on dish.id = request.dish left join species on species.id = dish.species";
+
+#[derive(FromRow, Debug, Serialize, Deserialize)]
+pub struct QueueRequest {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub request: Request,
+    pub station: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueTable {
+    pub desk: String,
+    pub requests: Vec<QueueRequest>
+    // oldest first
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueState {
+    pub state: i32,
+    pub tables: Vec<QueueTable>
+    // ordered by each table's oldest request
+}
+
+#[derive(FromRow, Debug, Serialize, Deserialize)]
+pub struct CompletedRequest {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub request: Request,
+    pub completed: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StationProgress {
+    pub station: i32,
+    pub outstanding: i32,
+    // lines not yet completed
+    pub completed: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableReadiness {
+    pub desk: String,
+    pub stations: Vec<StationProgress>,
+    pub ready: bool
+    // true once every station has completed all of its lines for the table
+}
+
+pub struct Kitchen;
+impl Kitchen {
+    pub async fn queue(db: &Pool<Sqlite>, station: Option<i32>) -> Vec<QueueState> {
+        // station None -> everything, otherwise only what that station makes
+        // rush and vip lines pull their table to the front
+        let active = sqlx::query_as::<_, QueueRequest>(&format!(
+            "select request.*, {STATION_OF} as station {FROM_ROUTED} where (request.state = $1 or request.state = $2) and request.held = 0 and ($3 is null or {STATION_OF} = $3) order by request.priority desc, request.created, request.id;"
+        ))
+            .bind(PENDING)
+            .bind(IN_KITCHEN)
+            .bind(station)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut result = vec![
+            QueueState { state: PENDING, tables: vec![] },
+            QueueState { state: IN_KITCHEN, tables: vec![] }
+        ];
+        for mut r in active {
+            r.request = Allergy::attach(db, r.request).await;
+            let group = result.iter_mut().find(|g| g.state == r.request.state).unwrap();
+            match group.tables.iter_mut().find(|t| t.desk == r.request.desk) {
+                Some(t) => t.requests.push(r),
+                None => group.tables.push(QueueTable {
+                    desk: r.request.desk.clone(),
+                    requests: vec![r]
+                })
+            }
+        }
+        result
+    }
+
+    pub async fn bump(db: &Pool<Sqlite>, request_id: i32) -> Result {
+        // pending -> in kitchen -> completed
+        match Request::fetch(db, request_id).await {
+            Some(r) => {
+                if r.held || !(PENDING..COMPLETED).contains(&r.state) {
+                    return Result::StateInvalid;
+                }
+
+                Request::set_state(db, request_id, r.state + 1).await;
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn bump_table(db: &Pool<Sqlite>, desk: String, station: Option<i32>) -> (Result, Vec<i32>) {
+        // a station bumping every line it has for one table
+        if Desk::fetch(db, &desk).await.is_none() {
+            return (Result::NoTable, vec![]);
+        }
+
+        let active = sqlx::query_as::<_, QueueRequest>(&format!(
+            "select request.*, {STATION_OF} as station {FROM_ROUTED} where request.desk = $1 and (request.state = $2 or request.state = $3) and request.held = 0 and ($4 is null or {STATION_OF} = $4);"
+        ))
+            .bind(&desk)
+            .bind(PENDING)
+            .bind(IN_KITCHEN)
+            .bind(station)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        if active.is_empty() {
+            return (Result::DoesntExist, vec![]);
+        }
+
+        let mut ids = vec![];
+        for r in active {
+            Request::set_state(db, r.request.id, r.request.state + 1).await;
+            ids.push(r.request.id);
+        }
+
+        (Result::Success, ids)
+    }
+
+    pub async fn ready(db: &Pool<Sqlite>, desk: String) -> Option<TableReadiness> {
+        Desk::fetch(db, &desk).await?;
+
+        let lines = sqlx::query_as::<_, QueueRequest>(&format!(
+            "select request.*, {STATION_OF} as station {FROM_ROUTED} where request.desk = $1 and request.state <= $2 and request.held = 0;"
+        ))
+            .bind(&desk)
+            .bind(COMPLETED)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut stations: Vec<StationProgress> = vec![];
+        for l in lines {
+            let index = match stations.iter().position(|s| s.station == l.station) {
+                Some(i) => i,
+                None => {
+                    stations.push(StationProgress { station: l.station, outstanding: 0, completed: 0 });
+                    stations.len() - 1
+                }
+            };
+
+            if l.request.state == COMPLETED {
+                stations[index].completed += 1;
+            } else {
+                stations[index].outstanding += 1;
+            }
+        }
+
+        Some(TableReadiness {
+            desk,
+            ready: !stations.is_empty() && stations.iter().all(|s| s.outstanding == 0),
+            stations
+        })
+    }
+
+    pub async fn recall(db: &Pool<Sqlite>, request_id: Option<i32>, station: Option<i32>) -> (Result, Option<i32>) {
+        // undoes the latest state change, either of one request or of the whole kitchen/station
+        // a request's first state row is its creation and cant be undone
+        // only a request's own latest row is a candidate, so a voided line is skipped rather than brought back
+        let last = match request_id {
+            Some(id) => sqlx::query_as::<_, RequestState>("select * from request_state where request = $1 order by id desc limit 1;")
+                .bind(id)
+                .fetch_optional(db)
+                .await
+                .unwrap(),
+            None => sqlx::query_as::<_, RequestState>(&format!(
+                "select s.* from request_state s join request on request.id = s.request join dish on dish.id = request.dish left join species on species.id = dish.species where s.id = (select max(id) from request_state where request = s.request) and s.state != $2 and exists (select 1 from request_state p where p.request = s.request and p.id < s.id) and ($1 is null or {STATION_OF} = $1) order by s.id desc limit 1;"
+            ))
+                .bind(station)
+                .bind(CANCELLED)
+                .fetch_optional(db)
+                .await
+                .unwrap()
+        };
+
+        let last = match last {
+            Some(l) => l,
+            None => {
+                return (Result::DoesntExist, None);
+            }
+        };
+        // a cancellation is undone by nobody, it went through void.rs
+        if last.state == CANCELLED {
+            return (Result::StateInvalid, None);
+        }
+
+        let states = Request::fetch_states(db, last.request).await;
+        if states.len() < 2 {
+            return (Result::StateInvalid, None);
+        }
+        let previous = &states[states.len() - 2];
+
+        sqlx::query("delete from request_state where id = $1;")
+            .bind(last.id)
+            .execute(db)
+            .await
+            .unwrap();
+
+        sqlx::query("update request set state = $1 where id = $2;")
+            .bind(previous.state)
+            .bind(last.request)
+            .execute(db)
+            .await
+            .unwrap();
+
+        (Result::Success, Some(last.request))
+    }
+
+    pub async fn history(db: &Pool<Sqlite>, limit: i32, station: Option<i32>) -> Vec<CompletedRequest> {
+        // most recently completed first
+        sqlx::query_as(&format!(
+            "select request.*, request_state.time as completed {FROM_ROUTED} join request_state on request.id = request_state.request where request.state = $1 and request_state.state = $1 and ($3 is null or {STATION_OF} = $3) order by request_state.id desc limit $2;"
+        ))
+            .bind(COMPLETED)
+            .bind(limit)
+            .bind(station)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[post("/?<station>", data="<login>")]
+pub async fn queue(db: &State<Pool<Sqlite>>, login: String, station: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::KITCHEN, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Kitchen::queue(db, station).await).unwrap()
+}
+
+#[post("/<request_id>", data="<login>")]
+pub async fn bump(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, request_id: i32) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::KITCHEN, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    let result = Kitchen::bump(db, request_id).await;
+    if result == Result::Success {
+        bus.publish_id(db, events::STATE_CHANGED, request_id).await;
+    }
+    result.to_string()
+}
+
+#[post("/<desk>?<station>", data="<login>")]
+pub async fn bump_table(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, desk: String, station: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::KITCHEN, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    let (result, ids) = Kitchen::bump_table(db, decode_uri(desk), station).await;
+    for id in ids {
+        bus.publish_id(db, events::STATE_CHANGED, id).await;
+    }
+    result.to_string()
+}
+
+#[post("/<desk>", data="<login>")]
+pub async fn ready(db: &State<Pool<Sqlite>>, login: String, desk: String) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::KITCHEN, staff::WAITER, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Kitchen::ready(db, decode_uri(desk)).await).unwrap()
+}
+
+#[post("/?<request_id>&<station>", data="<login>")]
+pub async fn recall(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, request_id: Option<i32>, station: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::KITCHEN, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    let (result, recalled) = Kitchen::recall(db, request_id, station).await;
+    if let Some(id) = recalled {
+        bus.publish_id(db, events::STATE_CHANGED, id).await;
+    }
+    result.to_string()
+}
+
+#[post("/?<limit>&<station>", data="<login>")]
+pub async fn history(db: &State<Pool<Sqlite>>, login: String, limit: Option<i32>, station: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::KITCHEN, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Kitchen::history(db, limit.unwrap_or(20), station).await).unwrap()
+}
+
+#[post("/<request_id>/<priority>", data="<login>")]
+pub async fn priority(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, request_id: i32, priority: i32) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    let result = Request::set_priority(db, request_id, priority).await;
+    if result == Result::Success {
+        bus.publish_id(db, events::PRIORITY_CHANGED, request_id).await;
+    }
+    result.to_string()
+}
@@ -1,4 +1,5 @@
 #[macro_use] extern crate rocket;
+use rocket::{data::{Limits, ToByteUnit}, fairing::AdHoc};
 use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
 
 mod utils;
@@ -9,8 +10,36 @@ mod validation;
 mod desk;
 mod dish;
 mod species;
+mod tag;
+mod picture;
+mod translation;
 
 mod request;
+mod allergy;
+mod idempotency;
+mod events;
+mod change;
+mod void;
+mod session;
+mod bill;
+mod discount;
+mod split;
+mod payment;
+mod refund;
+mod receipt;
+mod tip;
+mod gift_card;
+mod report;
+mod analytics;
+
+mod staff;
+mod station;
+mod kitchen;
+mod course;
+mod eta;
+mod alert;
+mod ticket;
+mod printer;
 
 #[get("/")]
 fn index() -> String {
@@ -19,11 +48,42 @@ fn index() -> String {
 
 #[launch]
 async fn rocket() -> _ {
-    rocket::custom(rocket::config::Config::figment().merge(("port", 8007)))
-        .manage(SqlitePool::connect_with(SqliteConnectOptions::new()
-            .filename("db")
-        ).await.unwrap())
-        .attach(cors::CORS)
+    let figment = rocket::config::Config::figment()
+        .merge(("port", 8007))
+        .merge(("limits", Limits::default()
+            .limit("file", picture::MAX_SIZE.bytes())
+            .limit("data-form", (picture::MAX_SIZE + 1024 * 1024).bytes())
+        ))
+        .join(("idempotency_window", idempotency::DEFAULT_WINDOW))
+        .join(("service_charge", bill::DEFAULT_SERVICE_CHARGE))
+        .join(("restaurant_name", receipt::DEFAULT_NAME))
+        .join(("utc_offset", utils::DEFAULT_UTC_OFFSET));
+
+    utils::set_utc_offset(figment.extract_inner("utc_offset").unwrap());
+
+    let db = SqlitePool::connect_with(SqliteConnectOptions::new()
+        .filename("db")
+    ).await.unwrap();
+    let bus = events::EventBus::new();
+
+    rocket::custom(figment.clone())
+        .manage(db.clone())
+        .manage(idempotency::IdempotencyConfig {
+            window: figment.extract_inner("idempotency_window").unwrap()
+        })
+        .manage(bill::BillConfig {
+            service_charge: figment.extract_inner("service_charge").unwrap()
+        })
+        .manage(receipt::ReceiptConfig {
+            name: figment.extract_inner("restaurant_name").unwrap()
+        })
+        .manage(Box::new(payment::LocalProvider) as payment::Provider)
+        .manage(bus.clone())
+        .attach(cors::Cors)
+        .attach(AdHoc::on_liftoff("background tasks", |_| Box::pin(async move {
+            rocket::tokio::spawn(printer::PrintJob::run(db.clone()));
+            rocket::tokio::spawn(alert::Alert::run(db, bus));
+        })))
         .mount("/", routes![index])
 
         // admin permissions
@@ -39,12 +99,125 @@ async fn rocket() -> _ {
         .mount("/dish/create", routes![dish::create])
         .mount("/dish/delete", routes![dish::delete])
         .mount("/dish/edit", routes![dish::edit])
+        .mount("/dish/prep_time", routes![dish::prep_time])
+        .mount("/dish/prices", routes![dish::prices])
+        .mount("/eta/prep_times", routes![eta::prep_times])
+
+        .mount("/tag/create", routes![tag::create])
+        .mount("/tag/delete", routes![tag::delete])
+        .mount("/tag/edit", routes![tag::edit])
+        .mount("/tag/assign", routes![tag::assign])
+        .mount("/tag/unassign", routes![tag::unassign])
+        .mount("/tag/assign_option", routes![tag::assign_option])
+        .mount("/tag/unassign_option", routes![tag::unassign_option])
+
+        .mount("/translation/set", routes![translation::set])
+        .mount("/translation/delete", routes![translation::delete])
+        .mount("/translation/fetch", routes![translation::fetch])
+        .mount("/translation/missing", routes![translation::missing])
+
+        .mount("/picture/upload_dish", routes![picture::upload_dish])
+        .mount("/picture/upload_species", routes![picture::upload_species])
+        .mount("/picture/delete", routes![picture::delete])
+
+        .mount("/staff/create", routes![staff::create])
+        .mount("/staff/delete", routes![staff::delete])
+        .mount("/staff/fetch_all", routes![staff::fetch_all])
+
+        .mount("/station/create", routes![station::create])
+        .mount("/station/delete", routes![station::delete])
+        .mount("/station/edit", routes![station::edit])
+        .mount("/station/assign_species", routes![station::assign_species])
+        .mount("/station/assign_dish", routes![station::assign_dish])
+        .mount("/station/fetch_all", routes![station::fetch_all])
+
+        .mount("/bill/tax_rate", routes![bill::tax_rate])
+        .mount("/bill/tax_rates", routes![bill::tax_rates])
+        .mount("/bill/species_tax", routes![bill::species_tax])
+        .mount("/bill/session", routes![bill::session])
+        .mount("/discount/create", routes![discount::create])
+        .mount("/discount/enable", routes![discount::enable])
+        .mount("/discount/disable", routes![discount::disable])
+        .mount("/discount/fetch_all", routes![discount::fetch_all])
+        .mount("/session/fetch_for_desk", routes![session::fetch_for_desk])
+        .mount("/payment/fetch_for_session", routes![payment::fetch_for_session])
+        .mount("/refund/fetch_for_session", routes![refund::fetch_for_session])
+        .mount("/refund/fetch_all", routes![refund::fetch_all])
+        .mount("/refund/reconcile", routes![refund::reconcile])
+        .mount("/receipt/fetch_all", routes![receipt::fetch_all])
+        .mount("/tip/pool", routes![tip::pool])
+        .mount("/gift_card/fetch", routes![gift_card::fetch])
+        .mount("/gift_card/history", routes![gift_card::history])
+        .mount("/gift_card/liability", routes![gift_card::liability])
+
+        .mount("/report/sales", routes![report::sales])
+        .mount("/report/summary", routes![report::summary])
+        .mount("/analytics/kitchen", routes![analytics::kitchen])
+        .mount("/analytics/slowest", routes![analytics::slowest])
+
+        .mount("/void/fetch_all", routes![void::fetch_all])
+        .mount("/void/waste", routes![void::waste])
+
+        .mount("/alert/threshold", routes![alert::threshold])
+        .mount("/alert/thresholds", routes![alert::thresholds])
+        .mount("/alert/breaches", routes![alert::breaches])
+
+        .mount("/course/assign_species", routes![course::assign_species])
+        .mount("/course/assign_dish", routes![course::assign_dish])
+
+        .mount("/printer/create", routes![printer::create])
+        .mount("/printer/delete", routes![printer::delete])
+        .mount("/printer/fetch_all", routes![printer::fetch_all])
+
+        // kitchen permissions
+        .mount("/kitchen/queue", routes![kitchen::queue])
+        .mount("/kitchen/bump", routes![kitchen::bump])
+        .mount("/kitchen/bump_table", routes![kitchen::bump_table])
+        .mount("/kitchen/ready", routes![kitchen::ready])
+        .mount("/kitchen/recall", routes![kitchen::recall])
+        .mount("/kitchen/history", routes![kitchen::history])
+        .mount("/kitchen/priority", routes![kitchen::priority])
+        .mount("/printer/jobs", routes![printer::jobs])
+        .mount("/printer/reprint", routes![printer::reprint])
+        .mount("/printer/reprint_table", routes![printer::reprint_table])
+        .mount("/course/fire", routes![course::fire])
+        .mount("/course/hold", routes![course::hold])
+        .mount("/course/timing", routes![course::timing])
+        .mount("/alert/active", routes![alert::active])
+        .mount("/bill/table", routes![bill::table])
+        .mount("/split/table", routes![split::table])
+        .mount("/payment/pay", routes![payment::pay])
+        .mount("/refund/payment", routes![refund::payment])
+        .mount("/refund/line", routes![refund::line])
+        .mount("/receipt/session", routes![receipt::session])
+        .mount("/tip/serve", routes![tip::serve])
+        .mount("/gift_card/issue", routes![gift_card::issue])
+        .mount("/gift_card/expire", routes![gift_card::expire])
+        .mount("/session/close", routes![session::close])
+        .mount("/void/request", routes![void::request])
+        .mount("/void/approve", routes![void::approve])
+        .mount("/void/reject", routes![void::reject])
+        .mount("/void/awaiting", routes![void::awaiting])
+        .mount("/events/kitchen", routes![events::kitchen])
+        .mount("/changes/staff", routes![change::for_staff])
 
         // table permissions
         .mount("/request/create", routes![request::create])
-        .mount("/request/delete", routes![request::delete])
+        .mount("/request/cart", routes![request::cart])
+        .mount("/request/cancel", routes![request::cancel])
+        .mount("/bill/fetch", routes![bill::fetch])
+        .mount("/split/seat", routes![split::seat])
+        .mount("/split/assign", routes![split::assign])
+        .mount("/split/fetch", routes![split::fetch])
+        .mount("/payment/table", routes![payment::table])
+        .mount("/discount/redeem", routes![discount::redeem])
+        .mount("/receipt/fetch", routes![receipt::fetch])
+        .mount("/tip/suggest", routes![tip::suggest])
+        .mount("/gift_card/balance", routes![gift_card::balance])
         .mount("/request/edit", routes![request::edit])
         .mount("/request/fetch", routes![request::fetch])
+        .mount("/events/table", routes![events::table])
+        .mount("/changes/table", routes![change::for_table])
 
         // no permission
         .mount("/species/fetch", routes![species::fetch])
@@ -53,4 +226,11 @@ async fn rocket() -> _ {
 
         .mount("/dish/fetch", routes![dish::fetch])
         .mount("/dish/fetch_all", routes![dish::fetch_all])
+
+        .mount("/tag/fetch_all", routes![tag::fetch_all])
+        .mount("/tag/effective", routes![tag::effective])
+
+        .mount("/picture/fetch", routes![picture::fetch])
+
+        .mount("/receipt/view", routes![receipt::view])
 }
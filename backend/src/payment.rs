@@ -0,0 +1,300 @@
+use std::time::Duration;
+
+use rocket::{tokio::time::sleep, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{bill::{Bill, BillConfig}, callback_result::Result, desk::Desk, gift_card::GiftCard, receipt::Receipt, session::Session, staff, tip::{self, Tip}, utils::{decode_uri, get_time, ValueInt}, validation::Validation};
+
+pub const CASH: i32 = 0;
+pub const CARD: i32 = 1;
+pub const EWALLET: i32 = 2;
+pub const GIFT_CARD: i32 = 3;
+pub const METHODS: [i32; 4] = [CASH, CARD, EWALLET, GIFT_CARD];
+
+pub const PENDING: i32 = 0;
+pub const COMPLETED: i32 = 1;
+pub const DECLINED: i32 = 2;
+
+#[rocket::async_trait]
+pub trait PaymentProvider: Send + Sync {
+    // anything that can take money off a card or wallet, a real terminal plugs in here
+    // Ok -> the provider's reference for the charge, Err -> why it was declined
+    async fn charge(&self, method: i32, amount: i64, reference: &str) -> std::result::Result<String, String>;
+    // reference -> what charge handed back for the original payment
+    async fn refund(&self, method: i32, amount: i64, reference: &str) -> std::result::Result<String, String>;
+}
+
+pub type Provider = Box<dyn PaymentProvider>;
+
+pub struct LocalProvider;
+// approves everything without talking to anyone, for development and testing
+// except amounts ending in 99 sen, which it declines so that path can be tried too
+
+#[rocket::async_trait]
+impl PaymentProvider for LocalProvider {
+    async fn charge(&self, _method: i32, amount: i64, reference: &str) -> std::result::Result<String, String> {
+        if amount % 100 == 99 {
+            return Err("declined by local provider".to_string());
+        }
+        Ok(format!("local-{reference}-{}", get_time()))
+    }
+
+    async fn refund(&self, _method: i32, _amount: i64, reference: &str) -> std::result::Result<String, String> {
+        Ok(format!("{reference}-refund-{}", get_time()))
+    }
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Payment {
+    pub id: i32,
+    pub session: i32,
+    pub method: i32,
+    // 0 -> cash
+    // 1 -> card
+    // 2 -> e-wallet
+    // 3 -> gift card, see gift_card.rs
+    pub amount: i64,
+    // what went towards the bill
+    pub tendered: i64,
+    pub tip: i64,
+    // on top of amount, goes to the tip pool and isnt revenue, see tip.rs
+    pub change: i64,
+    // tendered - amount - tip, only ever non zero for cash
+    pub reference: Option<String>,
+    // from the provider, the gift card's code, None for cash
+    pub status: i32,
+    // 0 -> pending, claimed against the bill while the charge is in flight
+    // 1 -> completed
+    // 2 -> declined, kept so failed attempts still show up
+    pub error: Option<String>,
+    pub staff: Option<String>,
+    // who took it, None when the table paid by itself
+    pub time: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentResponse {
+    pub result: Result,
+    pub payment: Option<Payment>,
+    pub outstanding: i64,
+    pub closed: bool
+    // true once this payment settled the bill and the session was closed
+}
+
+impl Payment {
+    // CREATE TABLE payment(id integer primary key autoincrement, session int, method int, amount int, tendered int, change int, reference varchar, status int, error varchar, staff varchar, time int);
+    // payment.tip int default 0
+    // CREATE TRIGGER payment_immutable_update BEFORE UPDATE ON payment WHEN old.status != 0 OR new.session != old.session OR new.method != old.method OR new.amount != old.amount OR new.tip != old.tip BEGIN SELECT RAISE(ABORT, 'payments are immutable'); END;
+    // a pending payment can only be settled, once completed or declined its never touched again
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn pay(db: &Pool<Sqlite>, provider: &Provider, config: &BillConfig, desk: &str, method: i32, amount: Option<i64>, tendered: Option<i64>, tip: Option<i64>, tip_rate: Option<i32>, code: Option<String>, staff: Option<String>) -> PaymentResponse {
+        // amount None -> whatever is left on the bill, or as much of it as the gift card covers
+        // code -> the gift card, only for method 3
+        let respond = |result: Result, outstanding: i64| PaymentResponse { result, payment: None, outstanding, closed: false };
+
+        if !METHODS.contains(&method) {
+            return respond(Result::MethodDoesntExist, 0);
+        }
+        let bill = match Bill::current(db, desk, config).await {
+            Some(b) => b,
+            None => {
+                return respond(Result::DoesntExist, 0);
+            }
+        };
+        if bill.outstanding <= 0 {
+            return respond(Result::BillSettled, bill.outstanding);
+        }
+
+        let code = code.unwrap_or_default();
+        let amount = match amount {
+            Some(a) => a,
+            None if method == GIFT_CARD => GiftCard::usable(db, &code).await.map_or(bill.outstanding, |g| g.balance.clamp(1, bill.outstanding)),
+            None => bill.outstanding
+        };
+        if amount <= 0 || amount > bill.outstanding {
+            return respond(Result::PaymentInvalid, bill.outstanding);
+        }
+        let tip = match tip::resolve(amount, tip, tip_rate) {
+            Ok(t) => t,
+            Err(e) => {
+                return respond(e, bill.outstanding);
+            }
+        };
+
+        let tendered = if method == CASH {
+            let tendered = tendered.unwrap_or(amount + tip);
+            if tendered < amount + tip {
+                return respond(Result::PaymentInvalid, bill.outstanding);
+            }
+            tendered
+        } else {
+            amount + tip
+        };
+
+        // the amount is claimed before any money moves, checked against everything else claimed
+        // or taken in the same statement, so two payments on one bill cant both take the last of it
+        let owed = bill.outstanding + bill.paid;
+        let claimed = sqlx::query("insert into payment(session, method, amount, tendered, tip, change, reference, status, error, staff, time) select $1, $2, $3, $4, $5, $6, null, $7, null, $8, $9 where (select coalesce(sum(amount), 0) from payment where session = $1 and status in ($10, $11)) + $3 <= $12;")
+            .bind(bill.session)
+            .bind(method)
+            .bind(amount)
+            .bind(tendered)
+            .bind(tip)
+            .bind(tendered - amount - tip)
+            .bind(if method == CASH { COMPLETED } else { PENDING })
+            .bind(&staff)
+            .bind(get_time())
+            .bind(PENDING)
+            .bind(COMPLETED)
+            .bind(owed)
+            .execute(db)
+            .await;
+        let id = match claimed {
+            Ok(r) if r.rows_affected() == 1 => r.last_insert_rowid() as i32,
+            Ok(_) => {
+                return respond(Result::PaymentInvalid, bill.outstanding);
+            }
+            Err(e) => {
+                println!("payment.rs; pay({desk}); error: {e}");
+                return respond(Result::PaymentInvalid, bill.outstanding);
+            }
+        };
+
+        if method != CASH {
+            let charged = if method == GIFT_CARD {
+                GiftCard::charge(db, &code, amount + tip, bill.session).await
+            } else {
+                provider.charge(method, amount + tip, &format!("s{}", bill.session)).await
+            };
+            let (status, reference, error) = match charged {
+                Ok(r) => (COMPLETED, Some(r), None),
+                Err(e) => (DECLINED, None, Some(e))
+            };
+            Payment::settle(db, id, status, reference, error).await;
+            if status == DECLINED {
+                return PaymentResponse { result: Result::PaymentDeclined, payment: Payment::fetch(db, id).await, outstanding: bill.outstanding, closed: false };
+            }
+        }
+        let payment = Payment::fetch(db, id).await;
+
+        if let Some(s) = &staff {
+            Tip::serve(db, bill.session, s).await;
+        }
+
+        let outstanding = Bill::compute(db, bill.session, config).await.map_or(0, |b| b.outstanding);
+        if outstanding <= 0 {
+            Session::close(db, config, bill.session).await;
+            Receipt::issue(db, config, bill.session).await;
+        }
+
+        PaymentResponse { result: Result::Success, payment, outstanding, closed: outstanding <= 0 }
+    }
+
+    async fn settle(db: &Pool<Sqlite>, id: i32, status: i32, reference: Option<String>, error: Option<String>) {
+        // the money has moved by now, so a busy database is waited out rather than given up on
+        // otherwise the row stays pending and shows up as such for whoever reconciles
+        for _ in 0..5 {
+            match sqlx::query("update payment set status = $2, reference = $3, error = $4 where id = $1 and status = $5;")
+                .bind(id)
+                .bind(status)
+                .bind(&reference)
+                .bind(&error)
+                .bind(PENDING)
+                .execute(db)
+                .await {
+                Ok(_) => {
+                    return;
+                }
+                Err(e) => {
+                    println!("payment.rs; settle({id}); error: {e}");
+                    sleep(Duration::from_millis(200)).await;
+                }
+            }
+        }
+    }
+
+    pub async fn paid(db: &Pool<Sqlite>, session: i32) -> i64 {
+        sqlx::query_as::<_, ValueInt>("select coalesce(sum(amount), 0) from payment where session = $1 and status = $2;")
+            .bind(session)
+            .bind(COMPLETED)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    pub async fn claimed(db: &Pool<Sqlite>, session: i32) -> i64 {
+        // paid, plus whatever is still being charged
+        sqlx::query_as::<_, ValueInt>("select coalesce(sum(amount), 0) from payment where session = $1 and status in ($2, $3);")
+            .bind(session)
+            .bind(PENDING)
+            .bind(COMPLETED)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Payment> {
+        match sqlx::query_as("select * from payment where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(p) => Some(p),
+            Err(e) => {
+                println!("payment.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_for_session(db: &Pool<Sqlite>, session: i32) -> Vec<Payment> {
+        sqlx::query_as("select * from payment where session = $1 order by id;")
+            .bind(session)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/<desk>/<method>?<amount>&<tendered>&<tip>&<tip_rate>&<code>", data="<login>")]
+pub async fn pay(db: &State<Pool<Sqlite>>, provider: &State<Provider>, config: &State<BillConfig>, login: String, desk: String, method: i32, amount: Option<i64>, tendered: Option<i64>, tip: Option<i64>, tip_rate: Option<i32>, code: Option<String>) -> String {
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    serde_json::to_string(&Payment::pay(db, provider.inner(), config.inner(), &decode_uri(desk), method, amount, tendered, tip, tip_rate, code, Some(login.id)).await).unwrap()
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/<method>?<amount>&<tip>&<tip_rate>&<code>", data="<table>")]
+pub async fn table(db: &State<Pool<Sqlite>>, provider: &State<Provider>, config: &State<BillConfig>, table: String, method: i32, amount: Option<i64>, tip: Option<i64>, tip_rate: Option<i32>, code: Option<String>) -> String {
+    // guests paying from the tablet, cash has to go through a waiter
+    let db = db.inner();
+    if method == CASH {
+        return Result::MethodDoesntExist.to_string();
+    }
+    let desk = match Desk::fetch(db, &table).await {
+        Some(d) => d,
+        None => {
+            return Result::NoTable.to_string();
+        }
+    };
+    if method == GIFT_CARD {
+        if let Err(e) = GiftCard::lookup(db, &desk.name, code.as_deref().unwrap_or_default()).await {
+            return e.to_string();
+        }
+    }
+    serde_json::to_string(&Payment::pay(db, provider.inner(), config.inner(), &desk.name, method, amount, None, tip, tip_rate, code, None).await).unwrap()
+}
+
+#[get("/<session>")]
+pub async fn fetch_for_session(db: &State<Pool<Sqlite>>, session: i32) -> String {
+    serde_json::to_string(&Payment::fetch_for_session(db.inner(), session).await).unwrap()
+}
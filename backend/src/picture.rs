@@ -0,0 +1,212 @@
+use std::path::{Path, PathBuf};
+
+use rocket::{form::{self, Form}, fs::{NamedFile, TempFile}, http::Status, tokio::task::spawn_blocking, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, dish::Dish, species::Species, utils::get_time};
+
+pub const PICTURE_DIR: &str = "pictures";
+pub const MAX_SIZE: u64 = 5 * 1024 * 1024;
+// the "file" limit in main.rs, rocket stops reading the upload past this
+pub const THUMBNAIL_SIZES: [u32; 2] = [128, 512];
+// thumbnails fit inside a square of this many pixels, aspect ratio is kept
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Picture {
+    pub kind: i32,
+    // 0 -> species
+    // 1 -> dish
+    pub target: i32,
+    pub filename: String
+    // "1_4_1718000000_9f3a01c2.png", thumbnails are "1_4_1718000000_9f3a01c2_128.png" etc
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PictureUrls {
+    pub original: String,
+    pub thumbnails: Vec<(u32, String)>
+}
+
+#[derive(FromForm)]
+pub struct Upload<'r> {
+    pub file: TempFile<'r>
+}
+
+impl Picture {
+    // CREATE TABLE picture(kind int, target int, filename varchar);
+
+    fn extension(upload: &TempFile<'_>) -> Option<&'static str> {
+        let content_type = upload.content_type()?;
+        if content_type.is_jpeg() {
+            Some("jpg")
+        } else if content_type.is_png() {
+            Some("png")
+        } else if content_type.is_webp() {
+            Some("webp")
+        } else {
+            None
+        }
+    }
+
+    fn thumbnail_name(filename: &str, size: u32) -> String {
+        match filename.rsplit_once('.') {
+            Some((stem, ext)) => format!("{stem}_{size}.{ext}"),
+            None => format!("{filename}_{size}")
+        }
+    }
+
+    fn files(filename: &str) -> Vec<String> {
+        let mut files = vec![filename.to_string()];
+        for size in THUMBNAIL_SIZES {
+            files.push(Picture::thumbnail_name(filename, size));
+        }
+        files
+    }
+
+    fn thumbnails(path: &Path, filename: &str) -> Result {
+        // content type is only what the client claims, decoding is the real check
+        let original = match image::open(path) {
+            Ok(i) => i,
+            Err(e) => {
+                println!("picture.rs; thumbnails({filename}); error: {e}");
+                return Result::PictureTypeUnsupported;
+            }
+        };
+
+        for size in THUMBNAIL_SIZES {
+            if let Err(e) = original.thumbnail(size, size).save(Path::new(PICTURE_DIR).join(Picture::thumbnail_name(filename, size))) {
+                println!("picture.rs; thumbnails({filename}); {size}; error: {e}");
+                return Result::PictureSaveFailed;
+            }
+        }
+        Result::Success
+    }
+
+    fn rejected(errors: &form::Errors<'_>) -> Result {
+        // the upload never reached us, rocket turned it away while parsing the form
+        if errors.iter().any(|e| e.status() == Status::PayloadTooLarge) {
+            Result::PictureTooLarge
+        } else {
+            Result::PictureTypeUnsupported
+        }
+    }
+
+    pub async fn upload(db: &Pool<Sqlite>, kind: i32, target: i32, mut upload: TempFile<'_>) -> Result {
+        let exists = match kind {
+            0 => Species::fetch(db, target).await.is_some(),
+            1 => Dish::fetch(db, target).await.is_some(),
+            _ => false
+        };
+        if !exists {
+            return Result::DoesntExist;
+        }
+
+        let extension = match Picture::extension(&upload) {
+            Some(e) => e,
+            None => {
+                return Result::PictureTypeUnsupported;
+            }
+        };
+
+        std::fs::create_dir_all(PICTURE_DIR).unwrap();
+        let filename = format!("{kind}_{target}_{}_{:08x}.{extension}", get_time(), rand::random::<u32>());
+        let path = Path::new(PICTURE_DIR).join(&filename);
+        if let Err(e) = upload.copy_to(&path).await {
+            println!("picture.rs; upload({kind}, {target}); error: {e}");
+            return Result::PictureSaveFailed;
+        }
+
+        // decoding and resizing are cpu bound, keep them off the async workers
+        let result = {
+            let (path, filename) = (path.clone(), filename.clone());
+            spawn_blocking(move || Picture::thumbnails(&path, &filename)).await.unwrap()
+        };
+        if result != Result::Success {
+            for f in Picture::files(&filename) {
+                let _ = std::fs::remove_file(Path::new(PICTURE_DIR).join(f));
+            }
+            return result;
+        }
+
+        // one picture per species/dish, a new upload replaces the old one
+        Picture::delete(db, kind, target).await;
+
+        sqlx::query("insert into picture(kind, target, filename) values($1, $2, $3);")
+            .bind(kind)
+            .bind(target)
+            .bind(filename)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn delete(db: &Pool<Sqlite>, kind: i32, target: i32) -> Result {
+        match Picture::fetch(db, kind, target).await {
+            Some(p) => {
+                for f in Picture::files(&p.filename) {
+                    if let Err(e) = std::fs::remove_file(Path::new(PICTURE_DIR).join(&f)) {
+                        println!("picture.rs; delete({kind}, {target}); {f}; error: {e}");
+                    }
+                }
+
+                sqlx::query("delete from picture where kind = $1 and target = $2;")
+                    .bind(kind)
+                    .bind(target)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, kind: i32, target: i32) -> Option<Picture> {
+        sqlx::query_as("select * from picture where kind = $1 and target = $2;")
+            .bind(kind)
+            .bind(target)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn urls(db: &Pool<Sqlite>, kind: i32, target: i32) -> Option<PictureUrls> {
+        Picture::fetch(db, kind, target).await.map(|p| PictureUrls {
+            original: format!("/picture/fetch/{}", p.filename),
+            thumbnails: THUMBNAIL_SIZES.iter()
+                .map(|s| (*s, format!("/picture/fetch/{}", Picture::thumbnail_name(&p.filename, *s))))
+                .collect()
+        })
+    }
+}
+
+#[post("/<id>", data = "<upload>")]
+pub async fn upload_dish(db: &State<Pool<Sqlite>>, id: i32, upload: form::Result<'_, Form<Upload<'_>>>) -> String {
+    match upload {
+        Ok(u) => Picture::upload(db.inner(), 1, id, u.into_inner().file).await.to_string(),
+        Err(e) => Picture::rejected(&e).to_string()
+    }
+}
+
+#[post("/<id>", data = "<upload>")]
+pub async fn upload_species(db: &State<Pool<Sqlite>>, id: i32, upload: form::Result<'_, Form<Upload<'_>>>) -> String {
+    match upload {
+        Ok(u) => Picture::upload(db.inner(), 0, id, u.into_inner().file).await.to_string(),
+        Err(e) => Picture::rejected(&e).to_string()
+    }
+}
+
+#[get("/<kind>/<target>")]
+pub async fn delete(db: &State<Pool<Sqlite>>, kind: i32, target: i32) -> String {
+    Picture::delete(db.inner(), kind, target).await.to_string()
+}
+
+#[get("/<filename..>")]
+pub async fn fetch(filename: PathBuf) -> Option<NamedFile> {
+    // PathBuf segments reject '..' so this stays inside PICTURE_DIR
+    NamedFile::open(Path::new(PICTURE_DIR).join(filename)).await.ok()
+}
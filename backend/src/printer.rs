@@ -0,0 +1,365 @@
+use std::time::Duration;
+
+use rocket::{tokio::{io::AsyncWriteExt, net::TcpStream, time::{sleep, timeout}}, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, request::{Request, CANCELLED}, staff, station::{Station, STATION_OF}, ticket::Ticket, utils::{decode_uri, get_time}, validation::Validation};
+
+pub const QUEUED: i32 = 0;
+pub const PRINTED: i32 = 1;
+pub const FAILED: i32 = 2;
+pub const ATTEMPTS: i32 = 5;
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Printer {
+    pub id: i32,
+    pub name: String,
+    pub address: String,
+    // "192.168.1.50:9100"
+    pub station: i32
+    // -1 -> prints whatever isnt routed to a station
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct PrintJob {
+    pub id: i32,
+    pub printer: i32,
+    pub desk: String,
+    pub requests: String,
+    // Vec<i32>
+    #[serde(skip_serializing)]
+    pub data: Vec<u8>,
+    // the rendered esc/pos bytes
+    pub state: i32,
+    // 0 -> queued
+    // 1 -> printed
+    // 2 -> failed, gave up after ATTEMPTS tries
+    pub attempts: i32,
+    pub error: Option<String>,
+    pub created: i32
+}
+
+impl Printer {
+    // CREATE TABLE printer(id integer primary key autoincrement, name varchar, address varchar, station int);
+    // CREATE TABLE print_job(id integer primary key autoincrement, printer int, desk varchar, requests varchar, data blob, state int, attempts int, error varchar, created int);
+
+    pub async fn create(db: &Pool<Sqlite>, name: String, address: String, station: i32) -> Result {
+        if station != -1 && Station::fetch(db, station).await.is_none() {
+            return Result::DoesntExist;
+        }
+
+        sqlx::query("insert into printer(name, address, station) values($1, $2, $3);")
+            .bind(name)
+            .bind(address)
+            .bind(station)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn delete(db: &Pool<Sqlite>, id: i32) -> Result {
+        match Printer::fetch(db, id).await {
+            Some(_) => {
+                sqlx::query("delete from print_job where printer = $1 and state = $2;")
+                    .bind(id)
+                    .bind(QUEUED)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                sqlx::query("delete from printer where id = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Printer> {
+        match sqlx::query_as("select * from printer where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(p) => Some(p),
+            Err(e) => {
+                println!("printer.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Printer> {
+        sqlx::query_as("select * from printer;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn queue_requests(db: &Pool<Sqlite>, ids: &[i32]) {
+        // one ticket per printer, each showing only the lines of its station
+        for p in Printer::fetch_all(db).await {
+            let mut requests = vec![];
+            for id in ids {
+                if Station::of_request(db, *id).await != p.station {
+                    continue;
+                }
+                // held lines print when their course is fired, voided ones never do
+                if let Some(r) = Request::fetch(db, *id).await.filter(|r| !r.held && r.state != CANCELLED) {
+                    requests.push(r);
+                }
+            }
+
+            // a table only ever orders for itself, so the first line speaks for the rest
+            let desk = match requests.first() {
+                Some(r) => r.desk.clone(),
+                None => continue
+            };
+
+            let heading = match Station::fetch(db, p.station).await {
+                Some(s) => s.name,
+                None => "kitchen".to_string()
+            };
+            let data = Ticket::render(&desk, &heading, get_time(), &Ticket::lines(db, &requests).await);
+            Printer::queue(db, p.id, &desk, &requests.iter().map(|r| r.id).collect::<Vec<i32>>(), data).await;
+        }
+    }
+
+    async fn queue(db: &Pool<Sqlite>, printer: i32, desk: &str, requests: &[i32], data: Vec<u8>) -> i32 {
+        sqlx::query("insert into print_job(printer, desk, requests, data, state, attempts, error, created) values($1, $2, $3, $4, $5, 0, null, $6);")
+            .bind(printer)
+            .bind(desk)
+            .bind(serde_json::to_string(requests).unwrap())
+            .bind(data)
+            .bind(QUEUED)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap()
+            .last_insert_rowid() as i32
+    }
+
+    pub async fn reprint(db: &Pool<Sqlite>, job: i32) -> Result {
+        // re-renders from the requests as they are now, rather than copying the old bytes
+        let job = match PrintJob::fetch(db, job).await {
+            Some(j) => j,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+
+        let mut requests = vec![];
+        for id in serde_json::from_str::<Vec<i32>>(&job.requests).unwrap_or_default() {
+            if let Some(r) = Request::fetch(db, id).await.filter(|r| r.state != CANCELLED) {
+                requests.push(r);
+            }
+        }
+        if requests.is_empty() {
+            return Result::DoesntExist;
+        }
+
+        let data = Ticket::render(&job.desk, "REPRINT", get_time(), &Ticket::lines(db, &requests).await);
+        Printer::queue(db, job.printer, &job.desk, &requests.iter().map(|r| r.id).collect::<Vec<i32>>(), data).await;
+        Result::Success
+    }
+
+    pub async fn reprint_table(db: &Pool<Sqlite>, desk: String, station: Option<i32>) -> Result {
+        // every line the table still has open, sent through the normal per-station routing
+        let ids = sqlx::query_as::<_, (i32,)>(&format!(
+            "select request.id from request join dish on dish.id = request.dish left join species on species.id = dish.species where request.desk = $1 and request.state < 2 and request.held = 0 and ($2 is null or {STATION_OF} = $2);"
+        ))
+            .bind(&desk)
+            .bind(station)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect::<Vec<i32>>();
+
+        if ids.is_empty() {
+            return Result::DoesntExist;
+        }
+
+        Printer::queue_requests(db, &ids).await;
+        Result::Success
+    }
+}
+
+impl PrintJob {
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<PrintJob> {
+        sqlx::query_as("select * from print_job where id = $1;")
+            .bind(id)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_recent(db: &Pool<Sqlite>, printer: Option<i32>, limit: i32) -> Vec<PrintJob> {
+        sqlx::query_as("select * from print_job where ($1 is null or printer = $1) order by id desc limit $2;")
+            .bind(printer)
+            .bind(limit)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn send(address: &str, data: &[u8]) -> std::result::Result<(), String> {
+        let mut stream = timeout(Duration::from_secs(5), TcpStream::connect(address)).await
+            .map_err(|_| "connect timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+
+        stream.write_all(data).await.map_err(|e| e.to_string())?;
+        stream.shutdown().await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub async fn run(db: Pool<Sqlite>) {
+        // background worker, started once the server is up
+        loop {
+            // a busy or locked db just waits for the next pass, the worker must never die
+            let jobs = match sqlx::query_as::<_, PrintJob>("select * from print_job where state = $1 order by id;")
+                .bind(QUEUED)
+                .fetch_all(&db)
+                .await {
+                Ok(j) => j,
+                Err(e) => {
+                    println!("printer.rs; run(); error: {e}");
+                    sleep(Duration::from_secs(2)).await;
+                    continue;
+                }
+            };
+
+            for job in jobs {
+                let printer = match Printer::fetch(&db, job.printer).await {
+                    Some(p) => p,
+                    None => continue
+                };
+
+                let (state, error) = match PrintJob::send(&printer.address, &job.data).await {
+                    Ok(_) => (PRINTED, None),
+                    Err(e) => {
+                        println!("printer.rs; run(); job {}; error: {e}", job.id);
+                        (if job.attempts + 1 >= ATTEMPTS { FAILED } else { QUEUED }, Some(e))
+                    }
+                };
+
+                // the ticket is already out, so keep trying rather than printing it twice on the next pass
+                for _ in 0..5 {
+                    match sqlx::query("update print_job set state = $1, attempts = attempts + 1, error = $2 where id = $3;")
+                        .bind(state)
+                        .bind(&error)
+                        .bind(job.id)
+                        .execute(&db)
+                        .await {
+                        Ok(_) => break,
+                        Err(e) => {
+                            println!("printer.rs; run(); job {}; error: {e}", job.id);
+                            sleep(Duration::from_millis(200)).await;
+                        }
+                    }
+                }
+            }
+
+            sleep(Duration::from_secs(2)).await;
+        }
+    }
+}
+
+#[get("/<name>/<address>/<station>")]
+pub async fn create(db: &State<Pool<Sqlite>>, name: String, address: String, station: i32) -> String {
+    Printer::create(db.inner(), decode_uri(name), decode_uri(address), station).await.to_string()
+}
+
+#[get("/<id>")]
+pub async fn delete(db: &State<Pool<Sqlite>>, id: i32) -> String {
+    Printer::delete(db.inner(), id).await.to_string()
+}
+
+#[get("/")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Printer::fetch_all(db.inner()).await).unwrap()
+}
+
+#[post("/?<printer>&<limit>", data="<login>")]
+pub async fn jobs(db: &State<Pool<Sqlite>>, login: String, printer: Option<i32>, limit: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&PrintJob::fetch_recent(db, printer, limit.unwrap_or(50)).await).unwrap()
+}
+
+#[post("/<job>", data="<login>")]
+pub async fn reprint(db: &State<Pool<Sqlite>>, login: String, job: i32) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    Printer::reprint(db, job).await.to_string()
+}
+
+#[post("/<desk>?<station>", data="<login>")]
+pub async fn reprint_table(db: &State<Pool<Sqlite>>, login: String, desk: String, station: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    Printer::reprint_table(db, decode_uri(desk), station).await.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+    use crate::{request::RUSH, ticket::TicketLine};
+
+    #[rocket::async_test]
+    async fn sends_rendered_ticket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let data = Ticket::render("a1", "grill", 0, &[TicketLine {
+            quantity: 2,
+            dish: "Burger".to_string(),
+            size: "Double".to_string(),
+            options: vec!["no onions".to_string()],
+            comment: "extra crispy".to_string(),
+            priority: RUSH,
+            allergies: vec![],
+            conflicts: vec!["nuts".to_string()]
+        }]);
+
+        let (sent, received) = rocket::tokio::join!(PrintJob::send(&address, &data), async {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = vec![];
+            socket.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        sent.unwrap();
+
+        assert_eq!(received, data);
+        assert!(received.starts_with(&[0x1b, 0x40]));
+        assert!(received.ends_with(&[0x1d, 0x56, 0x42, 0x00]));
+        let text = String::from_utf8_lossy(&received);
+        assert!(text.contains("TABLE a1"));
+        assert!(text.contains("*** RUSH ***"));
+        assert!(text.contains("2 x Burger (Double)"));
+        assert!(text.contains("!! CONTAINS NUTS"));
+        assert!(text.contains("   - no onions"));
+    }
+
+    #[rocket::async_test]
+    async fn reports_unreachable_printer() {
+        // bind then drop, so nothing is listening on the port
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        assert!(PrintJob::send(&address, b"x").await.is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use rocket::{response::content::RawHtml, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{bill::{Bill, BillConfig}, callback_result::Result, desk::Desk, payment::{Payment, CARD, CASH, COMPLETED, GIFT_CARD}, refund::Refund, session::Session, staff, ticket::WIDTH, utils::{decode_uri, format_money, format_rate, format_time, get_time}, validation::Validation};
+
+pub const DEFAULT_NAME: &str = "demeter";
+// printed at the top, override with ROCKET_RESTAURANT_NAME or restaurant_name in Rocket.toml
+
+pub const TABLE_WINDOW: i32 = 1800;
+// how long after settling the table can still pull up its receipt, unless a new party sits down first
+
+pub struct ReceiptConfig {
+    pub name: String
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    pub number: i32,
+    // 1, 2, 3.. with no gaps, handed out when the session is settled
+    pub session: i32,
+    pub issued: i32,
+    pub token: String,
+    // what goes in the guest's url, so receipts cant be looked up by counting
+    #[serde(skip)]
+    pub bill: Option<String>
+    // the Bill as it stood when the receipt was issued, None on receipts from before it was kept
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReceiptView {
+    pub receipt: Receipt,
+    pub bill: Bill,
+    pub text: String,
+    pub url: String
+}
+
+enum Row {
+    Centre(String),
+    Item(String, String),
+    Note(String),
+    Total(String, String),
+    Rule
+}
+
+fn method_name(method: i32) -> &'static str {
+    match method {
+        CASH => "Cash",
+        CARD => "Card",
+        GIFT_CARD => "Gift card",
+        _ => "E-wallet"
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl Receipt {
+    // CREATE TABLE receipt(number integer primary key, session int unique, issued int, token varchar);
+    // CREATE TRIGGER receipt_immutable_update BEFORE UPDATE ON receipt BEGIN SELECT RAISE(ABORT, 'receipts are immutable'); END;
+    // CREATE TRIGGER receipt_immutable_delete BEFORE DELETE ON receipt BEGIN SELECT RAISE(ABORT, 'receipts are immutable'); END;
+    // receipt.bill varchar
+
+    pub async fn issue(db: &Pool<Sqlite>, config: &BillConfig, session: i32) -> Option<Receipt> {
+        // only closed sessions get a number, and only ever one
+        // the next number is worked out inside the insert, sqlite runs one write at a time
+        // so two tables closing together cant take the same number or skip one
+        Session::fetch(db, session).await?.closed?;
+        // the bill goes in with it, a receipt reads the same however prices, rates or rules change later
+        let bill = Bill::compute(db, session, config).await?;
+
+        sqlx::query("insert or ignore into receipt(number, session, issued, token, bill) select coalesce(max(number), 0) + 1, $1, $2, $3, $4 from receipt;")
+            .bind(session)
+            .bind(get_time())
+            .bind(format!("{:016x}", rand::random::<u64>()))
+            .bind(serde_json::to_string(&bill).unwrap())
+            .execute(db)
+            .await
+            .unwrap();
+
+        Receipt::fetch_for_session(db, session).await
+    }
+
+    pub async fn fetch_for_session(db: &Pool<Sqlite>, session: i32) -> Option<Receipt> {
+        sqlx::query_as("select * from receipt where session = $1;")
+            .bind(session)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_by_token(db: &Pool<Sqlite>, token: &str) -> Option<Receipt> {
+        sqlx::query_as("select * from receipt where token = $1;")
+            .bind(token)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_range(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Receipt> {
+        sqlx::query_as("select * from receipt where issued >= $1 and issued < $2 order by number;")
+            .bind(from)
+            .bind(to)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn bill(db: &Pool<Sqlite>, config: &BillConfig, receipt: &Receipt) -> Option<Bill> {
+        // what was kept at issue, older receipts are worked out again
+        if let Some(b) = receipt.bill.as_deref().and_then(|b| serde_json::from_str(b).ok()) {
+            return Some(b);
+        }
+        Bill::compute(db, receipt.session, config).await
+    }
+
+    async fn rows(db: &Pool<Sqlite>, config: &ReceiptConfig, receipt: &Receipt, bill: &Bill) -> Vec<Row> {
+        // one layout for both the paper and the html receipt
+        let mut rows = vec![
+            Row::Centre(config.name.to_uppercase()),
+            Row::Centre(format!("RECEIPT #{:06}", receipt.number)),
+            Row::Centre(format!("{}  Table {}", format_time(bill.closed.unwrap_or(receipt.issued)), bill.desk)),
+            Row::Rule
+        ];
+
+        for l in bill.lines.iter() {
+            let title = if l.size.is_empty() {
+                format!("{} x {}", l.quantity, l.name)
+            } else {
+                format!("{} x {} ({})", l.quantity, l.name, l.size)
+            };
+            rows.push(Row::Item(title, format_money(l.amount)));
+            for o in l.options.iter() {
+                rows.push(Row::Note(format!("- {o}")));
+            }
+            for d in bill.discounts.iter().filter(|d| d.request == Some(l.request)) {
+                rows.push(Row::Item(format!("   {}", d.name), format_money(-d.amount)));
+            }
+        }
+
+        rows.push(Row::Rule);
+        rows.push(Row::Item("Subtotal".to_string(), format_money(bill.subtotal)));
+        for d in bill.discounts.iter().filter(|d| d.request.is_none()) {
+            rows.push(Row::Item(d.name.clone(), format_money(-d.amount)));
+        }
+        rows.push(Row::Item(format!("Service charge {}", format_rate(bill.service_charge_rate)), format_money(bill.service_charge)));
+        for t in bill.taxes.iter() {
+            rows.push(Row::Item(format!("{} {}", t.name, format_rate(t.rate)), format_money(t.amount)));
+        }
+        rows.push(Row::Total("TOTAL".to_string(), format_money(bill.total)));
+        rows.push(Row::Rule);
+
+        // only the money that had moved by the time it was issued, like the bill itself
+        for p in Payment::fetch_for_session(db, bill.session).await.iter().filter(|p| p.status == COMPLETED && p.time <= receipt.issued) {
+            if p.tip > 0 {
+                rows.push(Row::Item("Tip".to_string(), format_money(p.tip)));
+            }
+            rows.push(Row::Item(method_name(p.method).to_string(), format_money(p.tendered)));
+            if p.change > 0 {
+                rows.push(Row::Item("Change".to_string(), format_money(p.change)));
+            }
+        }
+        for r in Refund::fetch_for_session(db, bill.session).await.iter().filter(|r| r.status == COMPLETED && r.time <= receipt.issued) {
+            rows.push(Row::Item(format!("Refund {}", method_name(r.method)), format_money(-(r.amount + r.tip))));
+        }
+        if bill.outstanding != 0 {
+            rows.push(Row::Total("OUTSTANDING".to_string(), format_money(bill.outstanding)));
+        }
+
+        rows.push(Row::Rule);
+        rows.push(Row::Centre("thank you".to_string()));
+        rows
+    }
+
+    fn text(rows: &[Row]) -> String {
+        // plain text, WIDTH columns for 80mm paper
+        let pair = |left: &str, right: &str| {
+            let room = WIDTH.saturating_sub(right.chars().count() + 1);
+            let left = left.chars().take(room).collect::<String>();
+            format!("{left:<room$} {right}")
+        };
+
+        let mut out = vec![];
+        for row in rows {
+            match row {
+                Row::Centre(s) => out.push(format!("{:^WIDTH$}", s.chars().take(WIDTH).collect::<String>()).trim_end().to_string()),
+                Row::Item(l, r) | Row::Total(l, r) => out.push(pair(l, r)),
+                Row::Note(s) => out.push(format!("   {s}").chars().take(WIDTH).collect()),
+                Row::Rule => out.push("-".repeat(WIDTH))
+            }
+        }
+        out.join("\n") + "\n"
+    }
+
+    fn html(rows: &[Row], receipt: &Receipt) -> String {
+        let mut body = String::new();
+        for row in rows {
+            body += &match row {
+                Row::Centre(s) => format!("<tr><td colspan=\"2\" class=\"c\">{}</td></tr>", escape(s)),
+                Row::Item(l, r) => format!("<tr><td>{}</td><td class=\"r\">{}</td></tr>", escape(l), escape(r)),
+                Row::Total(l, r) => format!("<tr class=\"t\"><td>{}</td><td class=\"r\">{}</td></tr>", escape(l), escape(r)),
+                Row::Note(s) => format!("<tr><td colspan=\"2\" class=\"n\">{}</td></tr>", escape(s)),
+                Row::Rule => "<tr><td colspan=\"2\"><hr></td></tr>".to_string()
+            };
+        }
+
+        format!("<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>Receipt #{:06}</title><style>body{{font-family:monospace;max-width:26em;margin:1em auto;padding:0 1em}}table{{width:100%;border-collapse:collapse}}.c{{text-align:center}}.r{{text-align:right;white-space:nowrap}}.n{{padding-left:2em;color:#666}}.t{{font-weight:bold}}hr{{border:0;border-top:1px dashed #999}}</style></head><body><table>{body}</table></body></html>", receipt.number)
+    }
+
+    pub async fn view(db: &Pool<Sqlite>, bill_config: &BillConfig, config: &ReceiptConfig, session: i32) -> Option<ReceiptView> {
+        let receipt = Receipt::issue(db, bill_config, session).await?;
+        Receipt::render(db, bill_config, config, receipt).await
+    }
+
+    pub async fn render(db: &Pool<Sqlite>, bill_config: &BillConfig, config: &ReceiptConfig, receipt: Receipt) -> Option<ReceiptView> {
+        let bill = Receipt::bill(db, bill_config, &receipt).await?;
+        let text = Receipt::text(&Receipt::rows(db, config, &receipt, &bill).await);
+        let url = format!("/receipt/view/{}", receipt.token);
+        Some(ReceiptView { receipt, bill, text, url })
+    }
+
+    pub async fn just_closed(db: &Pool<Sqlite>, desk: &str) -> Option<Session> {
+        // the session the table settled last, as long as nobody has sat down since and its within TABLE_WINDOW
+        sqlx::query_as("select * from session where desk = $1 and closed > $2 and not exists (select 1 from session where desk = $1 and closed is null) order by closed desc, id desc limit 1;")
+            .bind(desk)
+            .bind(get_time() - TABLE_WINDOW)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[get("/<token>")]
+pub async fn view(db: &State<Pool<Sqlite>>, bill_config: &State<BillConfig>, config: &State<ReceiptConfig>, token: String) -> Option<RawHtml<String>> {
+    // the guest's copy, whoever has the link can see it
+    let db = db.inner();
+    let receipt = Receipt::fetch_by_token(db, &decode_uri(token)).await?;
+    let bill = Receipt::bill(db, bill_config.inner(), &receipt).await?;
+    let rows = Receipt::rows(db, config.inner(), &receipt, &bill).await;
+    Some(RawHtml(Receipt::html(&rows, &receipt)))
+}
+
+#[post("/", data="<table>")]
+pub async fn fetch(db: &State<Pool<Sqlite>>, bill_config: &State<BillConfig>, config: &State<ReceiptConfig>, table: String) -> String {
+    // the bill the table just settled, only ever one that already has a number
+    let db = db.inner();
+    let desk = match Desk::fetch(db, &table).await {
+        Some(d) => d,
+        None => {
+            return Result::NoTable.to_string();
+        }
+    };
+    let receipt = match Receipt::just_closed(db, &desk.name).await {
+        Some(s) => Receipt::fetch_for_session(db, s.id).await,
+        None => None
+    };
+    match receipt {
+        Some(r) => serde_json::to_string(&Receipt::render(db, bill_config.inner(), config.inner(), r).await).unwrap(),
+        None => Result::DoesntExist.to_string()
+    }
+}
+
+#[post("/<session>", data="<login>")]
+pub async fn session(db: &State<Pool<Sqlite>>, bill_config: &State<BillConfig>, config: &State<ReceiptConfig>, login: String, session: i32) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    match Receipt::view(db, bill_config.inner(), config.inner(), session).await {
+        Some(v) => serde_json::to_string(&v).unwrap(),
+        None => Result::DoesntExist.to_string()
+    }
+}
+
+#[get("/?<from>&<to>")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Receipt::fetch_range(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}
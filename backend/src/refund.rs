@@ -0,0 +1,388 @@
+use std::time::Duration;
+
+use rocket::{tokio::time::sleep, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{bill::{apply_rate, Bill, BillConfig}, callback_result::Result, gift_card::GiftCard, payment::{Payment, Provider, CASH, COMPLETED, DECLINED, GIFT_CARD, PENDING}, request::Request, session::Session, staff, utils::{get_time, ValueInt}, validation::Validation};
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Refund {
+    pub id: i32,
+    pub payment: i32,
+    pub session: i32,
+    pub request: Option<i32>,
+    // Some -> a single line was refunded, None -> money back off the payment
+    pub method: i32,
+    // same as the payment it came off
+    pub amount: i64,
+    pub tip: i64,
+    // given back off the payment's tip, kept apart since it was never revenue
+    pub reference: Option<String>,
+    // from the provider, None for cash
+    pub status: i32,
+    // same as payment.status, pending while the provider is being asked
+    pub reason: String,
+    pub staff: String,
+    pub time: i32
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct LineAdjustment {
+    pub request: i32,
+    pub session: i32,
+    pub amount: i64,
+    // the line's whole value, taken off the bill whatever was paid back for it
+    pub reason: String,
+    pub staff: String,
+    pub time: i32
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefundResponse {
+    pub result: Result,
+    pub refunds: Vec<Refund>,
+    // a line can come off more than one payment, or none if it wasnt paid for yet
+    pub adjustment: Option<LineAdjustment>,
+    pub outstanding: i64,
+    pub reopened: bool
+    // true when the refund left money owing and the session was opened again
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Reconciliation {
+    pub method: i32,
+    pub taken: i64,
+    pub refunded: i64,
+    pub net: i64,
+    pub tips: i64
+    // taken on top of net less any given back, owed to staff rather than revenue
+}
+
+impl Refund {
+    // CREATE TABLE refund(id integer primary key autoincrement, payment int, session int, request int, method int, amount int, reference varchar, reason varchar, staff varchar, time int);
+    // refund.status int default 1
+    // refund.tip int default 0
+    // CREATE TRIGGER refund_immutable_update BEFORE UPDATE ON refund WHEN old.status != 0 OR new.payment != old.payment OR new.amount != old.amount OR new.tip != old.tip BEGIN SELECT RAISE(ABORT, 'refunds are immutable'); END;
+    // CREATE TRIGGER refund_immutable_delete BEFORE DELETE ON refund BEGIN SELECT RAISE(ABORT, 'refunds are immutable'); END;
+    // CREATE TABLE line_adjustment(request integer primary key, session int, amount int, reason varchar, staff varchar, time int);
+    // CREATE TRIGGER line_adjustment_immutable_update BEFORE UPDATE ON line_adjustment BEGIN SELECT RAISE(ABORT, 'adjustments are immutable'); END;
+    // CREATE TRIGGER line_adjustment_immutable_delete BEFORE DELETE ON line_adjustment BEGIN SELECT RAISE(ABORT, 'adjustments are immutable'); END;
+    // same triggers on payment, adjustments are only ever added, never edited
+
+    pub async fn refunded(db: &Pool<Sqlite>, payment: i32) -> i64 {
+        sqlx::query_as::<_, ValueInt>("select coalesce(sum(amount), 0) from refund where payment = $1 and status = $2;")
+            .bind(payment)
+            .bind(COMPLETED)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    pub async fn refunded_session(db: &Pool<Sqlite>, session: i32) -> i64 {
+        sqlx::query_as::<_, ValueInt>("select coalesce(sum(amount), 0) from refund where session = $1 and status = $2;")
+            .bind(session)
+            .bind(COMPLETED)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    pub async fn refunded_tip(db: &Pool<Sqlite>, payment: i32) -> i64 {
+        sqlx::query_as::<_, ValueInt>("select coalesce(sum(tip), 0) from refund where payment = $1 and status = $2;")
+            .bind(payment)
+            .bind(COMPLETED)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    pub async fn adjusted(db: &Pool<Sqlite>, session: i32) -> i64 {
+        sqlx::query_as::<_, ValueInt>("select coalesce(sum(amount), 0) from line_adjustment where session = $1;")
+            .bind(session)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0
+    }
+
+    pub async fn adjusted_lines(db: &Pool<Sqlite>, session: i32) -> Vec<i32> {
+        sqlx::query_as::<_, ValueInt>("select request from line_adjustment where session = $1;")
+            .bind(session)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0 as i32)
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn issue(db: &Pool<Sqlite>, provider: &Provider, payment: &Payment, request: Option<i32>, amount: i64, tip: i64, reason: &str, staff: &str) -> std::result::Result<Refund, Result> {
+        // claimed before the provider is asked, checked against everything else claimed off
+        // the same payment in one statement, so two managers cant give back more than was taken
+        let claimed = sqlx::query("insert into refund(payment, session, request, method, amount, tip, reference, status, reason, staff, time) select $1, $2, $3, $4, $5, $13, null, $6, $7, $8, $9 where (select coalesce(sum(amount), 0) from refund where payment = $1 and status in ($10, $11)) + $5 <= $12 and (select coalesce(sum(tip), 0) from refund where payment = $1 and status in ($10, $11)) + $13 <= $14;")
+            .bind(payment.id)
+            .bind(payment.session)
+            .bind(request)
+            .bind(payment.method)
+            .bind(amount)
+            .bind(if payment.method == CASH { COMPLETED } else { PENDING })
+            .bind(reason)
+            .bind(staff)
+            .bind(get_time())
+            .bind(PENDING)
+            .bind(COMPLETED)
+            .bind(payment.amount)
+            .bind(tip)
+            .bind(payment.tip)
+            .execute(db)
+            .await;
+        let id = match claimed {
+            Ok(r) if r.rows_affected() == 1 => r.last_insert_rowid() as i32,
+            Ok(_) => {
+                return Err(Result::RefundInvalid);
+            }
+            Err(e) => {
+                println!("refund.rs; issue({}); error: {e}", payment.id);
+                return Err(Result::RefundInvalid);
+            }
+        };
+
+        if payment.method != CASH {
+            let original = payment.reference.as_deref().unwrap_or_default();
+            let refunded = if payment.method == GIFT_CARD {
+                GiftCard::credit(db, original, amount + tip, payment.session).await
+            } else {
+                provider.refund(payment.method, amount + tip, original).await
+            };
+            match refunded {
+                Ok(r) => Refund::finish(db, id, COMPLETED, Some(r)).await,
+                Err(e) => {
+                    println!("refund.rs; issue({}); error: {e}", payment.id);
+                    Refund::finish(db, id, DECLINED, None).await;
+                    return Err(Result::PaymentDeclined);
+                }
+            }
+        }
+
+        Ok(Refund::fetch(db, id).await.unwrap())
+    }
+
+    async fn finish(db: &Pool<Sqlite>, id: i32, status: i32, reference: Option<String>) {
+        // like Payment::settle, the money has moved so a busy database is waited out
+        for _ in 0..5 {
+            match sqlx::query("update refund set status = $2, reference = $3 where id = $1 and status = $4;")
+                .bind(id)
+                .bind(status)
+                .bind(&reference)
+                .bind(PENDING)
+                .execute(db)
+                .await {
+                Ok(_) => {
+                    return;
+                }
+                Err(e) => {
+                    println!("refund.rs; finish({id}); error: {e}");
+                    sleep(Duration::from_millis(200)).await;
+                }
+            }
+        }
+    }
+
+    async fn settle(db: &Pool<Sqlite>, config: &BillConfig, session: i32, result: Result, refunds: Vec<Refund>, adjustment: Option<LineAdjustment>) -> RefundResponse {
+        // a refund that leaves money owing opens the session again, unless the table already has a new party
+        let outstanding = Bill::compute(db, session, config).await.map(|b| b.outstanding).unwrap_or(0);
+        let reopened = outstanding > 0 && Session::reopen(db, session).await;
+        RefundResponse { result, refunds, adjustment, outstanding, reopened }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn payment(db: &Pool<Sqlite>, provider: &Provider, config: &BillConfig, payment_id: i32, amount: Option<i64>, tip: Option<i64>, reason: String, staff: String) -> RefundResponse {
+        // amount None -> whatever is left of the payment
+        // tip None -> none of it, a tip only goes back when asked for
+        let payment = match Payment::fetch(db, payment_id).await {
+            Some(p) if p.status == COMPLETED => p,
+            _ => {
+                return RefundResponse { result: Result::DoesntExist, refunds: vec![], adjustment: None, outstanding: 0, reopened: false };
+            }
+        };
+
+        let left = payment.amount - Refund::refunded(db, payment.id).await;
+        let amount = amount.unwrap_or(left);
+        let tip_left = payment.tip - Refund::refunded_tip(db, payment.id).await;
+        let tip = tip.unwrap_or(0);
+        if amount < 0 || amount > left || tip < 0 || tip > tip_left || amount + tip == 0 {
+            return RefundResponse { result: Result::RefundInvalid, refunds: vec![], adjustment: None, outstanding: 0, reopened: false };
+        }
+
+        match Refund::issue(db, provider, &payment, None, amount, tip, &reason, &staff).await {
+            Ok(r) => Refund::settle(db, config, payment.session, Result::Success, vec![r], None).await,
+            Err(e) => Refund::settle(db, config, payment.session, e, vec![], None).await
+        }
+    }
+
+    pub async fn line(db: &Pool<Sqlite>, provider: &Provider, config: &BillConfig, request_id: i32, reason: String, staff: String) -> RefundResponse {
+        // takes the line off the bill at what it actually cost, after discounts and with its service charge and tax
+        // then gives back whatever that leaves the table overpaid by, never more than the line
+        let fail = |result: Result| RefundResponse { result, refunds: vec![], adjustment: None, outstanding: 0, reopened: false };
+
+        let request = match Request::fetch(db, request_id).await {
+            Some(r) => r,
+            None => {
+                return fail(Result::DoesntExist);
+            }
+        };
+        let bill = match Bill::compute(db, request.session, config).await {
+            Some(b) => b,
+            None => {
+                return fail(Result::DoesntExist);
+            }
+        };
+        let line = match bill.lines.iter().find(|l| l.request == request_id) {
+            Some(l) => l,
+            None => {
+                return fail(Result::RefundInvalid);
+            }
+        };
+        let net = line.amount - line.discount;
+        let rate = bill.taxes.iter().find(|t| t.category == line.tax_category).map(|t| t.rate).unwrap_or(0);
+        let value = net + apply_rate(net, bill.service_charge_rate) + apply_rate(net, rate);
+
+        // the request is the key, so a line is only ever taken off once however many managers try
+        let adjusted = sqlx::query("insert or ignore into line_adjustment(request, session, amount, reason, staff, time) values($1, $2, $3, $4, $5, $6);")
+            .bind(request_id)
+            .bind(request.session)
+            .bind(value)
+            .bind(&reason)
+            .bind(&staff)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+        if adjusted == 0 {
+            return fail(Result::Exists);
+        }
+        let adjustment = Refund::adjustment(db, request_id).await;
+
+        // worked out again now the line is off, with whatever else happened to the bill meanwhile
+        let outstanding = Bill::compute(db, request.session, config).await.map_or(0, |b| b.outstanding);
+        let mut amount = value.min(-outstanding);
+
+        // newest payments first, a line paid across several payments is refunded across them
+        let payments = Payment::fetch_for_session(db, request.session).await;
+        let mut refunds = vec![];
+        for p in payments.iter().rev().filter(|p| p.status == COMPLETED) {
+            if amount <= 0 {
+                break;
+            }
+            let part = amount.min(p.amount - Refund::refunded(db, p.id).await);
+            if part <= 0 {
+                continue;
+            }
+
+            match Refund::issue(db, provider, p, Some(request_id), part, 0, &reason, &staff).await {
+                Ok(r) => refunds.push(r),
+                Err(e) => {
+                    return Refund::settle(db, config, request.session, e, refunds, adjustment).await;
+                }
+            }
+            amount -= part;
+        }
+
+        Refund::settle(db, config, request.session, Result::Success, refunds, adjustment).await
+    }
+
+    pub async fn adjustment(db: &Pool<Sqlite>, request: i32) -> Option<LineAdjustment> {
+        sqlx::query_as("select * from line_adjustment where request = $1;")
+            .bind(request)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Refund> {
+        match sqlx::query_as("select * from refund where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(r) => Some(r),
+            Err(e) => {
+                println!("refund.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_for_session(db: &Pool<Sqlite>, session: i32) -> Vec<Refund> {
+        sqlx::query_as("select * from refund where session = $1 order by id;")
+            .bind(session)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_range(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Refund> {
+        sqlx::query_as("select * from refund where time >= $1 and time < $2 order by id;")
+            .bind(from)
+            .bind(to)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn reconcile(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Reconciliation> {
+        // end of day, per method: what came in and what went back out in the range
+        sqlx::query_as("select method, sum(taken) as taken, sum(refunded) as refunded, sum(taken) - sum(refunded) as net, sum(tips) as tips from (select method, amount as taken, 0 as refunded, tip as tips from payment where status = $3 and time >= $1 and time < $2 union all select method, 0, amount, -tip from refund where status = $3 and time >= $1 and time < $2) group by method order by method;")
+            .bind(from)
+            .bind(to)
+            .bind(COMPLETED)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/<payment_id>?<amount>&<tip>&<reason>", data="<login>")]
+pub async fn payment(db: &State<Pool<Sqlite>>, provider: &State<Provider>, config: &State<BillConfig>, login: String, payment_id: i32, amount: Option<i64>, tip: Option<i64>, reason: Option<String>) -> String {
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &[staff::MANAGER]).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    serde_json::to_string(&Refund::payment(db, provider.inner(), config.inner(), payment_id, amount, tip, reason.unwrap_or_default(), login.id).await).unwrap()
+}
+
+#[post("/<request_id>?<reason>", data="<login>")]
+pub async fn line(db: &State<Pool<Sqlite>>, provider: &State<Provider>, config: &State<BillConfig>, login: String, request_id: i32, reason: Option<String>) -> String {
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &[staff::MANAGER]).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    serde_json::to_string(&Refund::line(db, provider.inner(), config.inner(), request_id, reason.unwrap_or_default(), login.id).await).unwrap()
+}
+
+#[get("/<session>")]
+pub async fn fetch_for_session(db: &State<Pool<Sqlite>>, session: i32) -> String {
+    serde_json::to_string(&Refund::fetch_for_session(db.inner(), session).await).unwrap()
+}
+
+#[get("/?<from>&<to>")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Refund::fetch_range(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}
+
+#[get("/?<from>&<to>")]
+pub async fn reconcile(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Refund::reconcile(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::{bill::{Bill, BillConfig}, callback_result::Result, dish::Dish, payment::COMPLETED, refund::Refund, request::{Request, CANCELLED}, session::Session, species::Species, ticket::Ticket, utils::{format_time, ValueInt}};
+
+pub const DAY: &str = "day";
+pub const DISH: &str = "dish";
+pub const SPECIES: &str = "species";
+pub const SIZE: &str = "size";
+pub const OPTION: &str = "option";
+pub const DESK: &str = "desk";
+pub const GROUPS: [&str; 6] = [DAY, DISH, SPECIES, SIZE, OPTION, DESK];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SalesRow {
+    pub key: String,
+    // "2024-06-10", "Burger", "Burger (Double)", "extra cheese", "a1"
+    pub lines: i64,
+    pub quantity: i64,
+    pub gross: i64,
+    // price x quantity as ordered
+    pub revenue: i64,
+    // gross less each line's share of the discounts, refunded lines count for nothing
+    pub sessions: i64,
+    pub average_ticket: i64
+    // revenue per session the row shows up in
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SalesSummary {
+    pub sessions: i64,
+    pub quantity: i64,
+    pub gross: i64,
+    pub revenue: i64,
+    // same as on the sales rows
+    pub taken: i64,
+    // payments, after discounts, service charge and tax
+    pub refunded: i64,
+    pub tips: i64,
+    pub average_ticket: i64
+    // (taken - refunded) per session that paid
+}
+
+#[derive(Default)]
+struct Tally {
+    lines: i64,
+    quantity: i64,
+    gross: i64,
+    revenue: i64,
+    sessions: HashSet<i32>
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn csv(header: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut out = header.join(",") + "\n";
+    for row in rows {
+        out += &(row.iter().map(|f| csv_field(f)).collect::<Vec<String>>().join(",") + "\n");
+    }
+    out
+}
+
+pub struct Report;
+impl Report {
+    async fn sold(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Request> {
+        // from, to -> when the request went in
+        sqlx::query_as("select * from request where state != $3 and created >= $1 and created < $2 order by id;")
+            .bind(from)
+            .bind(to)
+            .bind(CANCELLED)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn revenue(db: &Pool<Sqlite>, config: &BillConfig, requests: &[Request]) -> HashMap<i32, i64> {
+        // request id -> what the line actually brought in
+        // discounts come from each session's bill, bill wide ones are already spread over its lines there
+        // settled sessions are read from what they were charged at close, not todays rules
+        let mut result = HashMap::new();
+        for session in requests.iter().map(|r| r.session).collect::<BTreeSet<i32>>() {
+            if let Some(s) = Session::fetch(db, session).await {
+                for l in Bill::charged(db, &s, config).await.lines {
+                    result.insert(l.request, l.amount - l.discount);
+                }
+            }
+            for r in Refund::adjusted_lines(db, session).await {
+                result.insert(r, 0);
+            }
+        }
+        result
+    }
+
+    pub async fn sales(db: &Pool<Sqlite>, config: &BillConfig, group: &str, from: i32, to: i32) -> std::result::Result<Vec<SalesRow>, Result> {
+        if !GROUPS.contains(&group) {
+            return Err(Result::ReportInvalid);
+        }
+
+        let requests = Report::sold(db, from, to).await;
+        let lines = Ticket::lines(db, &requests).await;
+        let revenue = Report::revenue(db, config, &requests).await;
+
+        let mut species = HashMap::<i32, String>::new();
+        if group == SPECIES {
+            for r in requests.iter() {
+                if species.contains_key(&r.dish) {
+                    continue;
+                }
+                let name = match Dish::fetch(db, r.dish).await {
+                    Some(d) => Species::fetch(db, d.species).await.map(|s| s.name),
+                    None => None
+                };
+                species.insert(r.dish, name.unwrap_or_default());
+            }
+        }
+
+        let mut tally = BTreeMap::<String, Tally>::new();
+        for (r, l) in requests.iter().zip(lines) {
+            let keys = match group {
+                DAY => vec![format_time(r.created)[..10].to_string()],
+                DISH => vec![l.dish],
+                SPECIES => vec![species[&r.dish].clone()],
+                SIZE if l.size.is_empty() => vec![l.dish],
+                SIZE => vec![format!("{} ({})", l.dish, l.size)],
+                OPTION => l.options,
+                _ => vec![r.desk.clone()]
+            };
+
+            for key in keys {
+                let t = tally.entry(key).or_default();
+                t.lines += 1;
+                t.quantity += r.quantity as i64;
+                t.gross += r.price * r.quantity as i64;
+                t.revenue += revenue.get(&r.id).copied().unwrap_or(r.price * r.quantity as i64);
+                t.sessions.insert(r.session);
+            }
+        }
+
+        let mut rows = tally.into_iter()
+            .map(|(key, t)| SalesRow {
+                key,
+                lines: t.lines,
+                quantity: t.quantity,
+                gross: t.gross,
+                revenue: t.revenue,
+                sessions: t.sessions.len() as i64,
+                average_ticket: t.revenue / t.sessions.len().max(1) as i64
+            })
+            .collect::<Vec<SalesRow>>();
+        // days read best in order, everything else best sellers first
+        if group != DAY {
+            rows.sort_by(|a, b| b.revenue.cmp(&a.revenue).then(b.quantity.cmp(&a.quantity)).then(a.key.cmp(&b.key)));
+        }
+        Ok(rows)
+    }
+
+    pub async fn summary(db: &Pool<Sqlite>, config: &BillConfig, from: i32, to: i32) -> SalesSummary {
+        let requests = Report::sold(db, from, to).await;
+        let revenue = Report::revenue(db, config, &requests).await;
+        let sessions = requests.iter().map(|r| r.session).collect::<HashSet<i32>>().len() as i64;
+
+        let methods = Refund::reconcile(db, from, to).await;
+        let taken = methods.iter().map(|m| m.taken).sum::<i64>();
+        let refunded = methods.iter().map(|m| m.refunded).sum::<i64>();
+        let tips = methods.iter().map(|m| m.tips).sum::<i64>();
+        let paying = sqlx::query_as::<_, ValueInt>("select count(distinct session) from payment where status = $3 and time >= $1 and time < $2;")
+            .bind(from)
+            .bind(to)
+            .bind(COMPLETED)
+            .fetch_one(db)
+            .await
+            .unwrap()
+            .0;
+
+        SalesSummary {
+            sessions,
+            quantity: requests.iter().map(|r| r.quantity as i64).sum(),
+            gross: requests.iter().map(|r| r.price * r.quantity as i64).sum(),
+            revenue: requests.iter().map(|r| revenue.get(&r.id).copied().unwrap_or(r.price * r.quantity as i64)).sum(),
+            taken,
+            refunded,
+            tips,
+            average_ticket: (taken - refunded) / paying.max(1)
+        }
+    }
+}
+
+#[get("/<group>?<from>&<to>&<format>")]
+pub async fn sales(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, group: String, from: Option<i32>, to: Option<i32>, format: Option<String>) -> String {
+    // format -> "csv", anything else is json
+    let rows = match Report::sales(db.inner(), config.inner(), &group, from.unwrap_or(0), to.unwrap_or(i32::MAX)).await {
+        Ok(r) => r,
+        Err(e) => {
+            return e.to_string();
+        }
+    };
+
+    if format.as_deref() == Some("csv") {
+        csv(&[&group, "lines", "quantity", "gross", "revenue", "sessions", "average_ticket"], rows.into_iter()
+            .map(|r| vec![r.key, r.lines.to_string(), r.quantity.to_string(), r.gross.to_string(), r.revenue.to_string(), r.sessions.to_string(), r.average_ticket.to_string()])
+            .collect())
+    } else {
+        serde_json::to_string(&rows).unwrap()
+    }
+}
+
+#[get("/?<from>&<to>&<format>")]
+pub async fn summary(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, from: Option<i32>, to: Option<i32>, format: Option<String>) -> String {
+    let s = Report::summary(db.inner(), config.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await;
+
+    if format.as_deref() == Some("csv") {
+        csv(&["sessions", "quantity", "gross", "revenue", "taken", "refunded", "tips", "average_ticket"], vec![
+            [s.sessions, s.quantity, s.gross, s.revenue, s.taken, s.refunded, s.tips, s.average_ticket].iter().map(|x| x.to_string()).collect()
+        ])
+    } else {
+        serde_json::to_string(&s).unwrap()
+    }
+}
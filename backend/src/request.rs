@@ -1,8 +1,18 @@
 use rocket::State;
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, Pool, Sqlite};
+use sqlx::{prelude::FromRow, Pool, Sqlite, SqliteConnection};
 
-use crate::{callback_result::Result, desk::Desk, utils::{decode_uri, ValueString}};
+use crate::{callback_result::Result, desk::Desk, dish::Dish, events::{self, EventBus}, allergy::Allergy, course::Course, eta::Eta, idempotency::{Idempotency, IdempotencyConfig, IdempotencyKey}, printer::Printer, session::Session, tag::Tag, void::Void, utils::{decode_uri, get_time}};
+
+pub const PENDING: i32 = 0;
+pub const IN_KITCHEN: i32 = 1;
+pub const COMPLETED: i32 = 2;
+pub const CANCELLED: i32 = 3;
+
+pub const NORMAL: i32 = 0;
+pub const RUSH: i32 = 1;
+pub const VIP: i32 = 2;
+pub const PRIORITIES: [i32; 3] = [NORMAL, RUSH, VIP];
 
 #[derive(FromRow, Debug, Serialize, Deserialize)]
 pub struct Request {
@@ -12,120 +22,365 @@ pub struct Request {
     pub variant: String,
     pub size: i32,
     pub comment: String,
-    pub state: i32
+    pub state: i32,
     // 0 -> pending
     // 1 -> in kitchen
     // 2 -> completed
+    // 3 -> cancelled, only ever set through void.rs
+    pub quantity: i32,
+    pub created: i32,
+    pub course: i32,
+    // taken from the dish when ordered, see course.rs
+    pub held: bool,
+    // true -> waiting for the waiter to fire its course, the kitchen doesnt see it yet
+    pub priority: i32,
+    // 0 -> normal
+    // 1 -> rush
+    // 2 -> vip
+    // higher goes first on the kitchen queue
+    pub allergens: String,
+    // Vec<i32>, allergen tag ids the guest declared
+    pub price: i64,
+    // unit price in minor units, fixed when ordered
+    pub session: i32,
+    pub seat: i32,
+    // 0 -> shared by the table, see split.rs
+
+    #[sqlx(skip)]
+    pub eta: Option<i32>,
+    // unix time it should be ready, only filled where its shown to the table
+    #[sqlx(skip)]
+    pub allergies: Vec<Tag>,
+    // the declared allergens, see allergy.rs
+    #[sqlx(skip)]
+    pub conflicts: Vec<Tag>
+    // declared allergens the dish contains anyway, the order only went in because it was confirmed
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct RequestState {
+    pub id: i32,
+    pub request: i32,
+    pub state: i32,
+    pub time: i32
+    // one row every time a request enters a state, the first row is its creation
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CartLine {
+    pub dish: i32,
+    pub variant: Vec<Option<usize>>,
+    pub size: i32,
+    pub comment: String,
+    pub quantity: i32,
+    #[serde(default)]
+    pub priority: i32,
+    // always normal from the cart route
+    #[serde(default)]
+    pub allergens: Vec<String>,
+    // allergen names
+    #[serde(default)]
+    pub confirm: bool,
+    // true -> accept the line even though it contains a declared allergen
+    #[serde(default)]
+    pub seat: i32
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cart {
+    pub table: String,
+    pub lines: Vec<CartLine>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CartResponse {
+    pub result: Result,
+    pub lines: Vec<Result>,
+    // one per cart line, in the same order
+    pub ids: Vec<i32>
+    // ids of the inserted requests, empty unless everything went in
+}
+
 impl Request {
-    pub async fn check_variant(db: &Pool<Sqlite>, dish: i32, variant: Vec<Option<usize>>) -> bool {
+    pub fn check_variant(dish: &Dish, variant: &[Option<usize>]) -> bool {
         // TODO : unit test this
 
         // check length
         // check index per item
 
-        let variants = Request::parse_variants(
-        &sqlx::query_as::<_, ValueString>("select variants from dish where id = $1;")
-            .bind(dish)
-            .fetch_one(db)
-            .await
-            .unwrap().0
-        );
+        let variants = Request::parse_variants(&dish.variants);
 
         if variants.len() != variant.len() {
             return false;
         }
 
         for i in 0..variants.len() {
-            match variant[i] {
-                Some(o) => {
-                    if o >= variants[i].1.len() {
-                        return false;
-                    }
-                },
-                None => {}
+            if let Some(o) = variant[i] {
+                if o >= variants[i].1.len() {
+                    return false;
+                }
             }
         }
 
         true
     }
 
-    pub fn parse_variants(v: &String) -> Vec<(bool, Vec<String>)> {
+    pub fn parse_variants(v: &str) -> Vec<(bool, Vec<String>)> {
         serde_json::from_str(v).unwrap()
     }
 
-    pub fn parse_variant_selection(v: &String) -> Vec<Option<usize>> {
+    pub fn parse_variant_selection(v: &str) -> Vec<Option<usize>> {
         serde_json::from_str(v).unwrap()
     }
 
-    pub async fn check_size(db: &Pool<Sqlite>, dish: i32, size: i32) -> bool {
-        let sizes = sqlx::query_as::<_, ValueString>("select sizes from dish where id = $1;")
-            .bind(dish)
-            .fetch_one(db)
-            .await
-            .unwrap().0;
-
+    pub fn check_size(dish: &Dish, size: i32) -> bool {
         if size < 0 {
             return false;
         }
 
-        sizes.split(',').count() as i32 > size
+        dish.sizes.split(',').count() as i32 > size
     }
 
-    pub async fn create(db: &Pool<Sqlite>, desk: String, dish: i32, variant: Vec<Option<usize>>, size: i32, comment: String, state: i32) -> Result {
-        // CREATE TABLE request(id integer primary key autoincrement, desk varchar, dish int, variant int, size int, comment varchar, state int);
-        if !Request::check_variant(db, dish, variant.clone()).await {
+    pub async fn validate(db: &mut SqliteConnection, dish: i32, variant: &[Option<usize>], size: i32, quantity: i32) -> Result {
+        let dish = match Dish::fetch_row(&mut *db, dish).await {
+            Some(d) => d,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+
+        if !Request::check_variant(&dish, variant) {
             return Result::VariantDoesntExist;
         }
 
-        if !Request::check_size(db, dish, size).await {
+        if !Request::check_size(&dish, size) {
             return Result::SizeDoesntExist;
         }
 
-        sqlx::query("insert into request(desk, dish, variant, size, comment, state) values($1, $2, $3, $4, $5, $6);")
-            .bind(desk)
-            .bind(dish)
-            .bind(serde_json::to_string(&variant).unwrap())
-            .bind(size)
-            .bind(comment)
-            .bind(state)
-            .execute(db)
-            .await
-            .unwrap();
+        if quantity < 1 {
+            return Result::QuantityInvalid;
+        }
+
+        if dish.price(size).is_none() {
+            return Result::PriceInvalid;
+        }
 
         Result::Success
     }
 
-    pub async fn edit(db: &Pool<Sqlite>, request_id: i32, variant: Vec<Option<usize>>, size: i32, comment: String, state: i32) -> Result {
-        let dish;
-        match Request::fetch(&db, request_id).await {
-            Some(r) => {
-                dish = r;
-            },
-            None => {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(db: &Pool<Sqlite>, desk: String, dish: i32, variant: Vec<Option<usize>>, size: i32, comment: String, state: i32, quantity: i32, priority: i32, allergens: &[String], confirm: bool, seat: i32) -> (Result, Option<i32>) {
+        // CREATE TABLE request(id integer primary key autoincrement, desk varchar, dish int, variant int, size int, comment varchar, state int, quantity int default 1);
+        // a cart of one, so it gets the same checks inside the same kind of transaction
+        // cancelling goes through void.rs so it leaves a reason behind
+        if !(PENDING..=COMPLETED).contains(&state) {
+            return (Result::StateInvalid, None);
+        }
+
+        let line = CartLine { dish, variant, size, comment, quantity, priority, allergens: allergens.to_vec(), confirm, seat };
+        let response = Request::place(db, desk, vec![line], state).await;
+        match response.ids.first() {
+            Some(id) => (Result::Success, Some(*id)),
+            None => (response.lines.first().copied().unwrap_or(Result::CartInvalid), None)
+        }
+    }
+
+    pub async fn create_cart(db: &Pool<Sqlite>, desk: String, lines: Vec<CartLine>) -> CartResponse {
+        Request::place(db, desk, lines, PENDING).await
+    }
+
+    async fn place(db: &Pool<Sqlite>, desk: String, lines: Vec<CartLine>, state: i32) -> CartResponse {
+        // every line is checked and written inside one transaction, nothing goes in unless all of it does
+        // opening the session is the first write so it takes the lock, the dishes and tags
+        // checked below cant change until the lines are in
+        let mut tx = db.begin().await.unwrap();
+        let session = Session::open_for(&mut tx, &desk).await;
+
+        let mut results = vec![];
+        let mut allergens = vec![];
+        for line in lines.iter() {
+            let mut result = Request::validate(&mut tx, line.dish, &line.variant, line.size, line.quantity).await;
+            if result == Result::Success && !PRIORITIES.contains(&line.priority) {
+                result = Result::PriorityInvalid;
+            }
+            if result == Result::Success && line.seat < 0 {
+                result = Result::SeatInvalid;
+            }
+            if result == Result::Success {
+                match Allergy::check(&mut tx, line.dish, &line.variant, &line.allergens, line.confirm).await {
+                    Ok(a) => allergens.push(a),
+                    Err(e) => {
+                        result = e;
+                    }
+                }
+            }
+            results.push(result);
+        }
+
+        // dropping the transaction rolls back the session too
+        if lines.is_empty() || results.iter().any(|r| *r != Result::Success) {
+            return CartResponse {
+                result: Result::CartInvalid,
+                lines: results,
+                ids: vec![]
+            };
+        }
+
+        // courses are worked out up front, a main ordered alongside a starter waits for it
+        let mut courses = vec![];
+        for line in lines.iter() {
+            courses.push(Course::of_dish(&mut *tx, line.dish).await);
+        }
+        let earliest = courses.iter().min().copied();
+        let mut held = vec![];
+        for course in courses.iter() {
+            held.push(Course::should_hold(&mut *tx, &desk, *course, earliest).await);
+        }
+        let mut prices = vec![];
+        for line in lines.iter() {
+            prices.push(Dish::fetch_row(&mut *tx, line.dish).await.unwrap().price(line.size).unwrap());
+        }
+
+        let mut ids = vec![];
+        for (i, line) in lines.into_iter().enumerate() {
+            let id = sqlx::query("insert into request(desk, dish, variant, size, comment, state, quantity, created, course, held, priority, allergens, price, session, seat) values($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15);")
+                .bind(&desk)
+                .bind(line.dish)
+                .bind(serde_json::to_string(&line.variant).unwrap())
+                .bind(line.size)
+                .bind(line.comment)
+                .bind(state)
+                .bind(line.quantity)
+                .bind(get_time())
+                .bind(courses[i])
+                .bind(held[i])
+                .bind(line.priority)
+                .bind(serde_json::to_string(&allergens[i]).unwrap())
+                .bind(prices[i])
+                .bind(session)
+                .bind(line.seat)
+                .execute(&mut *tx)
+                .await
+                .unwrap()
+                .last_insert_rowid() as i32;
+
+            sqlx::query("insert into request_state(request, state, time) values($1, $2, $3);")
+                .bind(id)
+                .bind(state)
+                .bind(get_time())
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+
+            ids.push(id);
+        }
+        tx.commit().await.unwrap();
+
+        CartResponse {
+            result: Result::Success,
+            lines: results,
+            ids
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn edit(db: &Pool<Sqlite>, desk: &str, request_id: i32, variant: Vec<Option<usize>>, size: i32, comment: String, quantity: Option<i32>, confirm: bool) -> Result {
+        // quantity None -> left as it is
+        // confirm -> same as on place, accept a variant that brings back a declared allergen
+        // tables can only change what they ordered while the kitchen hasnt taken it yet
+        // state goes through the kitchen routes and void.rs, never through here
+        let request = match Request::fetch(db, request_id).await {
+            Some(r) if r.desk == desk => r,
+            _ => {
                 return Result::DoesntExist;
             }
+        };
+        if request.state != PENDING {
+            return Result::StateInvalid;
         }
+        let quantity = quantity.unwrap_or(request.quantity);
 
-        if !Request::check_variant(db, dish.id, variant.clone()).await {
-            return Result::VariantDoesntExist;
+        let mut conn = db.acquire().await.unwrap();
+        let result = Request::validate(&mut conn, request.dish, &variant, size, quantity).await;
+        if result != Result::Success {
+            return result;
         }
 
-        if !Request::check_size(db, dish.id, size).await {
-            return Result::SizeDoesntExist;
+        // the allergens declared on place still hold, a new variant can add one back in
+        let declared = serde_json::from_str::<Vec<i32>>(&request.allergens).unwrap_or_default();
+        if !confirm && !Allergy::conflicts(&mut conn, request.dish, &variant, &declared).await.is_empty() {
+            return Result::AllergenConflict;
         }
 
-        sqlx::query("update request set variant = $1, size = $2, comment = $3, state = $4 where id = $5;")
+        // checked again in the update in case the kitchen took it in the meantime
+        let price = Dish::fetch_row(&mut *conn, request.dish).await.unwrap().price(size).unwrap();
+        let updated = sqlx::query("update request set variant = $1, size = $2, comment = $3, quantity = $4, price = $7 where id = $5 and state = $6;")
             .bind(serde_json::to_string(&variant).unwrap())
             .bind(size)
             .bind(comment)
+            .bind(quantity)
+            .bind(request_id)
+            .bind(PENDING)
+            .bind(price)
+            .execute(&mut *conn)
+            .await
+            .unwrap()
+            .rows_affected();
+        if updated == 0 {
+            return Result::StateInvalid;
+        }
+
+        Result::Success
+    }
+
+    pub async fn log_state(db: &Pool<Sqlite>, request_id: i32, state: i32) {
+        // CREATE TABLE request_state(id integer primary key autoincrement, request int, state int, time int);
+        sqlx::query("insert into request_state(request, state, time) values($1, $2, $3);")
+            .bind(request_id)
             .bind(state)
-            .bind(dish.id)
+            .bind(get_time())
             .execute(db)
             .await
             .unwrap();
+    }
 
-        Result::Success
+    pub async fn set_priority(db: &Pool<Sqlite>, request_id: i32, priority: i32) -> Result {
+        if !PRIORITIES.contains(&priority) {
+            return Result::PriorityInvalid;
+        }
+
+        match Request::fetch(db, request_id).await {
+            Some(_) => {
+                sqlx::query("update request set priority = $1 where id = $2;")
+                    .bind(priority)
+                    .bind(request_id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn set_state(db: &Pool<Sqlite>, request_id: i32, state: i32) {
+        sqlx::query("update request set state = $1 where id = $2;")
+            .bind(state)
+            .bind(request_id)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Request::log_state(db, request_id, state).await;
+    }
+
+    pub async fn fetch_states(db: &Pool<Sqlite>, request_id: i32) -> Vec<RequestState> {
+        sqlx::query_as("select * from request_state where request = $1 order by id;")
+            .bind(request_id)
+            .fetch_all(db)
+            .await
+            .unwrap()
     }
 
     pub async fn fetch(db: &Pool<Sqlite>, request_id: i32) -> Option<Request> {
@@ -141,42 +396,107 @@ impl Request {
         }
     }
 
-    pub async fn delete(db: &Pool<Sqlite>, request_id: i32) -> Result {
-        match Request::fetch(db, request_id).await {
-            Some(_) => {
-                sqlx::query("delete from request where id = $1;")
-                    .bind(request_id)
-                    .execute(db)
-                    .await
-                    .unwrap();
+    pub async fn fetch_by_desk(db: &Pool<Sqlite>, desk: &str) -> Vec<Request> {
+        sqlx::query_as("select * from request where desk = $1;")
+            .bind(desk)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
 
-                Result::Success
-            },
-            None => Result::DoesntExist
-        }
+    pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Request> {
+        sqlx::query_as("select * from request;")
+            .fetch_all(db)
+            .await
+            .unwrap()
     }
 }
 
-#[post("/<dish>/<variant>/<size>/<comment>/<state>", data="<table>")]
-pub async fn create(db: &State<Pool<Sqlite>>, table: String, dish: i32, variant: String, size: i32, comment: String, state: i32) -> String {
+#[allow(clippy::too_many_arguments)]
+#[post("/<dish>/<variant>/<size>/<comment>/<state>?<quantity>&<allergens>&<confirm>&<seat>", data="<table>")]
+pub async fn create(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, config: &State<IdempotencyConfig>, key: IdempotencyKey, table: String, dish: i32, variant: String, size: i32, comment: String, state: i32, quantity: Option<i32>, allergens: Option<String>, confirm: Option<bool>, seat: Option<i32>) -> String {
+    // allergens -> "nuts,dairy"
+    // tables always order at normal priority, rush and vip are for staff through /kitchen/priority
     let db = db.inner();
     let variant = Request::parse_variant_selection(&variant);
     match Desk::fetch(db, &table).await {
         Some(d) => {
-            Request::create(&db, d.name, dish, variant, size, decode_uri(comment), state).await.to_string()
+            if let Some(k) = &key.0 {
+                if let Some(response) = Idempotency::begin(db, k, &d.name, config.window).await {
+                    return response;
+                }
+            }
+
+            let (result, id) = Request::create(
+                db, d.name.clone(), dish, variant, size, decode_uri(comment), state, quantity.unwrap_or(1),
+                NORMAL, &Tag::parse_names(&allergens.unwrap_or_default()), confirm.unwrap_or(false), seat.unwrap_or(0)
+            ).await;
+            let response = result.to_string();
+
+            if let Some(id) = id {
+                bus.publish_id(db, events::CREATED, id).await;
+                Printer::queue_requests(db, &[id]).await;
+            }
+
+            if let Some(k) = &key.0 {
+                Idempotency::finish(db, k, &d.name, &id.into_iter().collect::<Vec<i32>>(), &response).await;
+            }
+            response
         },
         None => Result::NoTable.to_string()
     }
 }
 
-#[post("/<request_id>/<variant>/<size>/<comment>/<state>", data="<table>")]
-pub async fn edit(db: &State<Pool<Sqlite>>, table: String, request_id: i32, variant: String, size: i32, comment: String, state: i32) -> String {
+#[post("/", data="<cart>")]
+pub async fn cart(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, config: &State<IdempotencyConfig>, key: IdempotencyKey, cart: String) -> String {
+    // body is a json Cart, the table name travels inside it
+    let db = db.inner();
+    let cart = match serde_json::from_str::<Cart>(&cart) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("request.rs; cart(); error: {e}");
+            return Result::CartInvalid.to_string();
+        }
+    };
+    match Desk::fetch(db, &cart.table).await {
+        Some(d) => {
+            if let Some(k) = &key.0 {
+                if let Some(response) = Idempotency::begin(db, k, &d.name, config.window).await {
+                    return response;
+                }
+            }
+
+            // whatever priority the guest sent, see create
+            let lines = cart.lines.into_iter().map(|l| CartLine { priority: NORMAL, ..l }).collect();
+            let result = Request::create_cart(db, d.name.clone(), lines).await;
+            let response = serde_json::to_string(&result).unwrap();
+
+            for id in result.ids.iter() {
+                bus.publish_id(db, events::CREATED, *id).await;
+            }
+            Printer::queue_requests(db, &result.ids).await;
+
+            if let Some(k) = &key.0 {
+                Idempotency::finish(db, k, &d.name, &result.ids, &response).await;
+            }
+            response
+        },
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/<request_id>/<variant>/<size>/<comment>/<state>?<quantity>&<confirm>", data="<table>")]
+pub async fn edit(db: &State<Pool<Sqlite>>, table: String, request_id: i32, variant: String, size: i32, comment: String, state: i32, quantity: Option<i32>, confirm: Option<bool>) -> String {
+    // state -> has to be 0, pending lines are the only ones a table can edit
+    // confirm -> true to keep a variant that contains a declared allergen
     let db = db.inner();
     let variant = Request::parse_variant_selection(&variant);
+    if state != PENDING {
+        return Result::StateInvalid.to_string();
+    }
     match Desk::fetch(db, &table).await {
-        Some(_) => {
-            Request::edit(db, request_id, variant, size, decode_uri(comment), state).await.to_string()
-        },
+        Some(d) => Request::edit(db, &d.name, request_id, variant, size, decode_uri(comment), quantity, confirm.unwrap_or(false)).await.to_string(),
         None => Result::NoTable.to_string()
     }
 }
@@ -186,21 +506,27 @@ pub async fn fetch(db: &State<Pool<Sqlite>>, table: String, request_id: i32) ->
     let db = db.inner();
     match Desk::fetch(db, &table).await {
         Some(_) => {
-            serde_json::to_string(&Request::fetch(db, request_id).await).unwrap()
+            let result = match Request::fetch(db, request_id).await {
+                Some(r) => Some(Allergy::attach(db, Eta::attach(db, r).await).await),
+                None => None
+            };
+            serde_json::to_string(&result).unwrap()
         },
         None => Result::NoTable.to_string()
     }
 }
 
-#[post("/<request_id>", data="<table>")]
-pub async fn delete(db: &State<Pool<Sqlite>>, table: String, request_id: i32) -> String {
+#[post("/<request_id>/<reason>", data="<table>")]
+pub async fn cancel(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, table: String, request_id: i32, reason: i32) -> String {
     let db = db.inner();
     match Desk::fetch(db, &table).await {
-        Some(_) => {
-            Request::delete(db, request_id).await.to_string()
+        Some(d) => {
+            let result = Void::by_table(db, &d.name, request_id, reason).await;
+            if result == Result::Success {
+                bus.publish_id(db, events::CANCELLED, request_id).await;
+            }
+            result.to_string()
         },
         None => Result::NoTable.to_string()
     }
 }
-
-
@@ -0,0 +1,112 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Executor, Pool, Sqlite, SqliteConnection};
+
+use crate::{bill::{Bill, BillConfig}, callback_result::Result, staff, utils::{decode_uri, get_time}, validation::Validation};
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i32,
+    pub desk: String,
+    pub opened: i32,
+    pub closed: Option<i32>
+    // None while the party is still seated
+}
+
+impl Session {
+    // CREATE TABLE session(id integer primary key autoincrement, desk varchar, opened int, closed int);
+    // CREATE UNIQUE INDEX session_open ON session(desk) WHERE closed IS NULL;
+    // a table's first request opens a session, settling the bill closes it
+
+    pub async fn current<'e, E: Executor<'e, Database = Sqlite>>(db: E, desk: &str) -> Option<Session> {
+        sqlx::query_as("select * from session where desk = $1 and closed is null order by id desc limit 1;")
+            .bind(desk)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn open_for(db: &mut SqliteConnection, desk: &str) -> i32 {
+        // session_open only lets a desk have one open session, so when two requests race
+        // to open it one insert is ignored and both pick up the same session
+        sqlx::query("insert or ignore into session(desk, opened, closed) values($1, $2, null);")
+            .bind(desk)
+            .bind(get_time())
+            .execute(&mut *db)
+            .await
+            .unwrap();
+
+        Session::current(&mut *db, desk).await.unwrap().id
+    }
+
+    pub async fn close(db: &Pool<Sqlite>, config: &BillConfig, id: i32) {
+        // the bill is kept as it stands now, see Bill::snapshot
+        let closed = sqlx::query("update session set closed = $1 where id = $2 and closed is null;")
+            .bind(get_time())
+            .bind(id)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+        if closed == 1 {
+            Bill::snapshot(db, id, config).await;
+        }
+    }
+
+    pub async fn reopen(db: &Pool<Sqlite>, id: i32) -> bool {
+        // false if its still open or the table has already been given to someone else
+        // session_open refuses it in the second case, which the ignore turns into no rows
+        let reopened = sqlx::query("update or ignore session set closed = null where id = $1 and closed is not null;")
+            .bind(id)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected() == 1;
+        if reopened {
+            Bill::drop_snapshot(db, id).await;
+        }
+        reopened
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Session> {
+        match sqlx::query_as("select * from session where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                println!("session.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_for_desk(db: &Pool<Sqlite>, desk: &str) -> Vec<Session> {
+        sqlx::query_as("select * from session where desk = $1 order by id desc;")
+            .bind(desk)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[get("/<desk>")]
+pub async fn fetch_for_desk(db: &State<Pool<Sqlite>>, desk: String) -> String {
+    serde_json::to_string(&Session::fetch_for_desk(db.inner(), &decode_uri(desk)).await).unwrap()
+}
+
+#[post("/<desk>", data="<login>")]
+pub async fn close(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, login: String, desk: String) -> String {
+    // clears a table by hand, e.g. a party that walked out
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    match Session::current(db, &decode_uri(desk)).await {
+        Some(s) => {
+            Session::close(db, config.inner(), s.id).await;
+            Result::Success.to_string()
+        },
+        None => Result::DoesntExist.to_string()
+    }
+}
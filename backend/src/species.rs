@@ -2,12 +2,23 @@ use rocket::State;
 use serde::{Deserialize, Serialize};
 use sqlx::{prelude::FromRow, Pool, Sqlite};
 
-use crate::{callback_result::Result, utils::decode_uri};
+use crate::{callback_result::Result, picture::{Picture, PictureUrls}, translation::{Locale, Translation}, utils::decode_uri};
 
 #[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
 pub struct Species {
     pub id: i32,
-    pub name: String
+    pub name: String,
+    pub station: i32,
+    // -1 -> not routed to any station
+    pub course: i32,
+    // 0 -> starter
+    // 1 -> main
+    // 2 -> dessert
+    pub tax_category: i32,
+    // see bill.rs
+
+    #[sqlx(skip)]
+    pub picture: Option<PictureUrls>
 }
 impl Species {
     pub async fn create(db: &Pool<Sqlite>, name: String) -> Result {
@@ -31,13 +42,16 @@ impl Species {
         match Species::fetch(db, id).await {
             Some(_) => {
                 sqlx::query("update dish set species = -1 where species = $1;")
-                    .bind(&id)
+                    .bind(id)
                     .execute(db)
                     .await
                     .unwrap();
 
+                Translation::delete_species(db, id).await;
+                Picture::delete(db, 0, id).await;
+
                 sqlx::query("delete from species where id = $1;")
-                    .bind(&id)
+                    .bind(id)
                     .execute(db)
                     .await
                     .unwrap();
@@ -53,7 +67,7 @@ impl Species {
             Some(_) => {
                 sqlx::query("update species set name = $1 where id = $2;")
                     .bind(new_name)
-                    .bind(&id)
+                    .bind(id)
                     .execute(db)
                     .await
                     .unwrap();
@@ -65,11 +79,11 @@ impl Species {
     }
 
     pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Species> {
-        match sqlx::query_as("select * from species where id = $1;")
-            .bind(&id)
+        match sqlx::query_as::<_, Species>("select * from species where id = $1;")
+            .bind(id)
             .fetch_one(db)
             .await {
-            Ok(s) => Some(s),
+            Ok(s) => Some(Species::attach_picture(db, s).await),
             Err(e) => {
                 println!("species.rs; fetch({id}); error: {e}");
                 None
@@ -78,11 +92,11 @@ impl Species {
     }
 
     pub async fn fetch_by_name(db: &Pool<Sqlite>, name: &String) -> Option<Species> {
-        match sqlx::query_as("select * from species where name = $1;")
+        match sqlx::query_as::<_, Species>("select * from species where name = $1;")
             .bind(name)
             .fetch_one(db)
             .await {
-            Ok(s) => Some(s),
+            Ok(s) => Some(Species::attach_picture(db, s).await),
             Err(e) => {
                 println!("species.rs; fetch_by_name({name}); error: {e}");
                 None
@@ -91,10 +105,21 @@ impl Species {
     }
 
     pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Species> {
-        sqlx::query_as("select * from species;")
+        let species = sqlx::query_as::<_, Species>("select * from species;")
             .fetch_all(db)
             .await
-            .unwrap()
+            .unwrap();
+
+        let mut result = vec![];
+        for s in species {
+            result.push(Species::attach_picture(db, s).await);
+        }
+        result
+    }
+
+    async fn attach_picture(db: &Pool<Sqlite>, mut species: Species) -> Species {
+        species.picture = Picture::urls(db, 0, species.id).await;
+        species
     }
 }
 
@@ -114,16 +139,32 @@ pub async fn edit(db: &State<Pool<Sqlite>>, id: i32, new_name: String) -> String
 }
 
 #[get("/")]
-pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
-    serde_json::to_string(&Species::fetch_all(db.inner()).await).unwrap()
+pub async fn fetch_all(db: &State<Pool<Sqlite>>, locale: Locale) -> String {
+    let db = db.inner();
+    let mut result = vec![];
+    for s in Species::fetch_all(db).await {
+        result.push(Translation::localise_species(db, s, &locale.0).await);
+    }
+    serde_json::to_string(&result).unwrap()
 }
 
 #[get("/<id>")]
-pub async fn fetch(db: &State<Pool<Sqlite>>, id: i32) -> String {
-    serde_json::to_string(&Species::fetch(db.inner(), id).await).unwrap()
+pub async fn fetch(db: &State<Pool<Sqlite>>, id: i32, locale: Locale) -> String {
+    let db = db.inner();
+    let result = match Species::fetch(db, id).await {
+        Some(s) => Some(Translation::localise_species(db, s, &locale.0).await),
+        None => None
+    };
+    serde_json::to_string(&result).unwrap()
 }
 
 #[get("/<name>")]
-pub async fn fetch_by_name(db: &State<Pool<Sqlite>>, name: String) -> String {
-    serde_json::to_string(&Species::fetch_by_name(db.inner(), &decode_uri(name)).await).unwrap()
+pub async fn fetch_by_name(db: &State<Pool<Sqlite>>, name: String, locale: Locale) -> String {
+    // name is matched against the untranslated name
+    let db = db.inner();
+    let result = match Species::fetch_by_name(db, &decode_uri(name)).await {
+        Some(s) => Some(Translation::localise_species(db, s, &locale.0).await),
+        None => None
+    };
+    serde_json::to_string(&result).unwrap()
 }
@@ -0,0 +1,316 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::{bill::{Bill, BillConfig}, callback_result::Result, desk::Desk, request::Request, session::Session, staff, utils::ValueInt, validation::Validation};
+
+pub const SEAT: &str = "seat";
+pub const ITEM: &str = "item";
+pub const EVEN: &str = "even";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitLine {
+    pub request: i32,
+    pub amount: i64
+    // this payer's part of the line
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitShare {
+    pub payer: i32,
+    // seat number, or payer number for item and even splits
+    pub lines: Vec<SplitLine>,
+    pub subtotal: i64,
+    // after discounts
+    pub service_charge: i64,
+    pub tax: i64,
+    pub total: i64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Split {
+    pub mode: String,
+    pub bill: Bill,
+    pub shares: Vec<SplitShare>
+    // totals always add up to bill.total
+}
+
+pub fn allocate(amount: i64, weights: &[i64], first: usize) -> Vec<i64> {
+    // largest remainder, so the parts always add back up to amount exactly
+    // all zero weights -> split evenly
+    // first -> who wins ties, rotated between calls so the spare cents dont all land on one payer
+    if weights.is_empty() {
+        return vec![];
+    }
+    let weights = if weights.iter().all(|w| *w <= 0) { vec![1; weights.len()] } else { weights.iter().map(|w| (*w).max(0)).collect() };
+    let sum = weights.iter().sum::<i64>();
+
+    let mut parts = weights.iter().map(|w| (amount * w).div_euclid(sum)).collect::<Vec<i64>>();
+    let mut order = (0..weights.len()).collect::<Vec<usize>>();
+    // biggest remainder first, ties go round from first
+    let n = weights.len();
+    order.sort_by_key(|i| (-(amount * weights[*i]).rem_euclid(sum), (*i + n - first % n) % n));
+
+    let left = amount - parts.iter().sum::<i64>();
+    for i in order.into_iter().take(left as usize) {
+        parts[i] += 1;
+    }
+    parts
+}
+
+impl Split {
+    // CREATE TABLE split_item(session int, request int, payer int);
+    // an item with several payers is shared equally between them
+
+    pub async fn set_seat(db: &Pool<Sqlite>, desk: &str, request_id: i32, seat: i32) -> Result {
+        // seat 0 -> shared by the whole table
+        match Request::fetch(db, request_id).await {
+            Some(r) if r.desk == desk => {
+                if seat < 0 {
+                    return Result::SeatInvalid;
+                }
+
+                sqlx::query("update request set seat = $1 where id = $2;")
+                    .bind(seat)
+                    .bind(request_id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            _ => Result::DoesntExist
+        }
+    }
+
+    pub async fn assign(db: &Pool<Sqlite>, desk: &str, request_id: i32, payers: Vec<i32>) -> Result {
+        // empty payers takes the item back to being shared by everyone
+        let request = match Request::fetch(db, request_id).await {
+            Some(r) if r.desk == desk => r,
+            _ => {
+                return Result::DoesntExist;
+            }
+        };
+        if payers.iter().any(|p| *p < 1) {
+            return Result::SplitInvalid;
+        }
+
+        sqlx::query("delete from split_item where request = $1;")
+            .bind(request_id)
+            .execute(db)
+            .await
+            .unwrap();
+
+        for p in payers {
+            sqlx::query("insert into split_item(session, request, payer) values($1, $2, $3);")
+                .bind(request.session)
+                .bind(request_id)
+                .bind(p)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+
+        Result::Success
+    }
+
+    async fn payers_of(db: &Pool<Sqlite>, request_id: i32) -> Vec<i32> {
+        sqlx::query_as::<_, ValueInt>("select distinct payer from split_item where request = $1 order by payer;")
+            .bind(request_id)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0 as i32)
+            .collect()
+    }
+
+    pub async fn compute(db: &Pool<Sqlite>, bill: Bill, mode: &str, n: Option<i32>) -> std::result::Result<Split, Result> {
+        // weights[line][payer], how each line is divided
+        let (payers, weights): (Vec<i32>, Vec<Vec<i64>>) = match mode {
+            SEAT => {
+                let mut seats = vec![];
+                for l in bill.lines.iter() {
+                    let seat = Request::fetch(db, l.request).await.map(|r| r.seat).unwrap_or(0);
+                    seats.push(seat);
+                }
+
+                let mut payers = seats.iter().copied().filter(|s| *s > 0).collect::<Vec<i32>>();
+                payers.sort();
+                payers.dedup();
+                if payers.is_empty() {
+                    return Err(Result::SplitInvalid);
+                }
+
+                // shared lines (seat 0) are spread over every seat
+                let weights = seats.iter()
+                    .map(|s| payers.iter().map(|p| if *s == 0 || s == p { 1 } else { 0 }).collect())
+                    .collect();
+                (payers, weights)
+            },
+            ITEM => {
+                let mut assigned = vec![];
+                for l in bill.lines.iter() {
+                    assigned.push(Split::payers_of(db, l.request).await);
+                }
+
+                let mut payers = assigned.iter().flatten().copied().collect::<Vec<i32>>();
+                payers.sort();
+                payers.dedup();
+                if payers.is_empty() {
+                    return Err(Result::SplitInvalid);
+                }
+
+                // unassigned lines are spread over every payer
+                let weights = assigned.iter()
+                    .map(|a| payers.iter().map(|p| if a.is_empty() || a.contains(p) { 1 } else { 0 }).collect())
+                    .collect();
+                (payers, weights)
+            },
+            EVEN => {
+                let n = n.unwrap_or(0);
+                if n < 1 {
+                    return Err(Result::SplitInvalid);
+                }
+                ((1..=n).collect(), bill.lines.iter().map(|_| vec![1; n as usize]).collect())
+            },
+            _ => {
+                return Err(Result::SplitInvalid);
+            }
+        };
+
+        let mut shares = payers.iter()
+            .map(|p| SplitShare { payer: *p, lines: vec![], subtotal: 0, service_charge: 0, tax: 0, total: 0 })
+            .collect::<Vec<SplitShare>>();
+
+        // every line, then the service charge and each tax, is allocated on its own
+        // so each of them reconciles, and so does the sum
+        let mut taxable = vec![vec![0; payers.len()]; bill.taxes.len()];
+        for (k, (line, w)) in bill.lines.iter().zip(weights.iter()).enumerate() {
+            for (i, amount) in allocate(line.amount - line.discount, w, k).into_iter().enumerate() {
+                if w[i] == 0 {
+                    continue;
+                }
+                shares[i].lines.push(SplitLine { request: line.request, amount });
+                shares[i].subtotal += amount;
+
+                if let Some(t) = bill.taxes.iter().position(|t| t.category == line.tax_category) {
+                    taxable[t][i] += amount;
+                }
+            }
+        }
+
+        let subtotals = shares.iter().map(|s| s.subtotal).collect::<Vec<i64>>();
+        for (i, amount) in allocate(bill.service_charge, &subtotals, 0).into_iter().enumerate() {
+            shares[i].service_charge = amount;
+        }
+        for (t, tax) in bill.taxes.iter().enumerate() {
+            for (i, amount) in allocate(tax.amount, &taxable[t], t + 1).into_iter().enumerate() {
+                shares[i].tax += amount;
+            }
+        }
+        for s in shares.iter_mut() {
+            s.total = s.subtotal + s.service_charge + s.tax;
+        }
+
+        Ok(Split {
+            mode: mode.to_string(),
+            bill,
+            shares
+        })
+    }
+
+    pub async fn current(db: &Pool<Sqlite>, desk: &str, config: &BillConfig, mode: &str, n: Option<i32>) -> std::result::Result<Split, Result> {
+        let session = Session::current(db, desk).await.ok_or(Result::DoesntExist)?;
+        let bill = Bill::compute(db, session.id, config).await.ok_or(Result::DoesntExist)?;
+        Split::compute(db, bill, mode, n).await
+    }
+}
+
+#[post("/<request_id>/<seat>", data="<table>")]
+pub async fn seat(db: &State<Pool<Sqlite>>, table: String, request_id: i32, seat: i32) -> String {
+    let db = db.inner();
+    match Desk::fetch(db, &table).await {
+        Some(d) => Split::set_seat(db, &d.name, request_id, seat).await.to_string(),
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[post("/<request_id>?<payers>", data="<table>")]
+pub async fn assign(db: &State<Pool<Sqlite>>, table: String, request_id: i32, payers: Option<String>) -> String {
+    // payers -> "1,2", leave out to unassign
+    let db = db.inner();
+    let payers = match payers.unwrap_or_default().split(',').filter(|x| !x.trim().is_empty()).map(|x| x.trim().parse::<i32>()).collect::<std::result::Result<Vec<i32>, _>>() {
+        Ok(p) => p,
+        Err(_) => {
+            return Result::SplitInvalid.to_string();
+        }
+    };
+    match Desk::fetch(db, &table).await {
+        Some(d) => Split::assign(db, &d.name, request_id, payers).await.to_string(),
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[post("/<mode>?<n>", data="<table>")]
+pub async fn fetch(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, table: String, mode: String, n: Option<i32>) -> String {
+    let db = db.inner();
+    match Desk::fetch(db, &table).await {
+        Some(d) => match Split::current(db, &d.name, config.inner(), &mode, n).await {
+            Ok(s) => serde_json::to_string(&s).unwrap(),
+            Err(e) => e.to_string()
+        },
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[post("/<desk>/<mode>?<n>", data="<login>")]
+pub async fn table(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, login: String, desk: String, mode: String, n: Option<i32>) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        return Result::NoPermission.to_string();
+    }
+    match Split::current(db, &desk, config.inner(), &mode, n).await {
+        Ok(s) => serde_json::to_string(&s).unwrap(),
+        Err(e) => e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_reconciles() {
+        for amount in [0, 1, 99, 100, 1001, 12345, -250] {
+            for weights in [vec![1], vec![1, 1, 1], vec![3, 1, 1, 2], vec![1000, 1, 7], vec![0, 5, 0]] {
+                for first in 0..4 {
+                    let parts = allocate(amount, &weights, first);
+                    assert_eq!(parts.len(), weights.len());
+                    assert_eq!(parts.iter().sum::<i64>(), amount, "{amount} over {weights:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn allocate_follows_weights() {
+        assert_eq!(allocate(1000, &[3, 1], 0), vec![750, 250]);
+        // the cent left over goes to the biggest remainder
+        assert_eq!(allocate(100, &[2, 1], 0), vec![67, 33]);
+        assert_eq!(allocate(500, &[0, 5, 0], 0), vec![0, 500, 0]);
+        assert_eq!(allocate(100, &[], 0), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn allocate_zero_weights_split_evenly() {
+        assert_eq!(allocate(90, &[0, 0, 0], 0), vec![30, 30, 30]);
+    }
+
+    #[test]
+    fn allocate_rotates_ties() {
+        assert_eq!(allocate(100, &[1, 1, 1], 0), vec![34, 33, 33]);
+        assert_eq!(allocate(100, &[1, 1, 1], 1), vec![33, 34, 33]);
+        assert_eq!(allocate(100, &[1, 1, 1], 5), vec![33, 33, 34]);
+    }
+}
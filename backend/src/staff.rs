@@ -0,0 +1,94 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, utils::decode_uri, validation::Validation};
+
+pub const KITCHEN: i32 = 0;
+pub const WAITER: i32 = 1;
+pub const MANAGER: i32 = 2;
+pub const ROLES: [i32; 3] = [KITCHEN, WAITER, MANAGER];
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Staff {
+    pub id: String,
+    pub role: i32
+    // 0 -> kitchen
+    // 1 -> waiter
+    // 2 -> manager
+}
+impl Staff {
+    // CREATE TABLE staff(id varchar primary key, secret varchar, role int);
+    // secret is stored as sha256 hex and never read back out
+
+    pub async fn create(db: &Pool<Sqlite>, id: String, secret: String, role: i32) -> Result {
+        if !ROLES.contains(&role) {
+            return Result::RoleDoesntExist;
+        }
+
+        match Staff::fetch(db, &id).await {
+            Some(_) => Result::Exists,
+            None => {
+                sqlx::query("insert into staff(id, secret, role) values($1, $2, $3);")
+                    .bind(id)
+                    .bind(Validation::hash_secret(&secret))
+                    .bind(role)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            }
+        }
+    }
+
+    pub async fn delete(db: &Pool<Sqlite>, id: String) -> Result {
+        match Staff::fetch(db, &id).await {
+            Some(_) => {
+                sqlx::query("delete from staff where id = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: &str) -> Option<Staff> {
+        match sqlx::query_as("select id, role from staff where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                println!("staff.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Staff> {
+        sqlx::query_as("select id, role from staff;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[get("/<id>/<secret>/<role>")]
+pub async fn create(db: &State<Pool<Sqlite>>, id: String, secret: String, role: i32) -> String {
+    Staff::create(db.inner(), decode_uri(id), decode_uri(secret), role).await.to_string()
+}
+
+#[get("/<id>")]
+pub async fn delete(db: &State<Pool<Sqlite>>, id: String) -> String {
+    Staff::delete(db.inner(), decode_uri(id)).await.to_string()
+}
+
+#[get("/")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Staff::fetch_all(db.inner()).await).unwrap()
+}
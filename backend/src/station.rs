@@ -0,0 +1,184 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, dish::Dish, species::Species, utils::{decode_uri, ValueInt}};
+
+pub const STATION_OF: &str = "(case when dish.station != -1 then dish.station else coalesce(species.station, -1) end)";
+// sql for the station a request lands on, needs dish and species joined in
+// "from request join dish on dish.id = request.dish left join species on species.id = dish.species"
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Station {
+    pub id: i32,
+    pub name: String
+    // grill, bar, dessert, ...
+}
+impl Station {
+    // CREATE TABLE station(id integer primary key autoincrement, name varchar);
+
+    pub async fn create(db: &Pool<Sqlite>, name: String) -> Result {
+        match Station::fetch_by_name(db, &name).await {
+            Some(_) => Result::Exists,
+            None => {
+                sqlx::query("insert into station(name) values($1);")
+                    .bind(name)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            }
+        }
+    }
+
+    pub async fn delete(db: &Pool<Sqlite>, id: i32) -> Result {
+        match Station::fetch(db, id).await {
+            Some(_) => {
+                sqlx::query("update species set station = -1 where station = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                sqlx::query("update dish set station = -1 where station = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                sqlx::query("delete from station where id = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn edit(db: &Pool<Sqlite>, id: i32, name: String) -> Result {
+        match Station::fetch(db, id).await {
+            Some(_) => {
+                sqlx::query("update station set name = $1 where id = $2;")
+                    .bind(name)
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn assign_species(db: &Pool<Sqlite>, species: i32, station: i32) -> Result {
+        // station -1 takes the species off its station
+        if Species::fetch(db, species).await.is_none() || (station != -1 && Station::fetch(db, station).await.is_none()) {
+            return Result::DoesntExist;
+        }
+
+        sqlx::query("update species set station = $1 where id = $2;")
+            .bind(station)
+            .bind(species)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn assign_dish(db: &Pool<Sqlite>, dish: i32, station: i32) -> Result {
+        // station -1 sends the dish back to its species' station
+        if Dish::fetch(db, dish).await.is_none() || (station != -1 && Station::fetch(db, station).await.is_none()) {
+            return Result::DoesntExist;
+        }
+
+        sqlx::query("update dish set station = $1 where id = $2;")
+            .bind(station)
+            .bind(dish)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Station> {
+        match sqlx::query_as("select * from station where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                println!("station.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_by_name(db: &Pool<Sqlite>, name: &str) -> Option<Station> {
+        match sqlx::query_as("select * from station where name = $1;")
+            .bind(name)
+            .fetch_one(db)
+            .await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                println!("station.rs; fetch_by_name({name}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn of_request(db: &Pool<Sqlite>, request_id: i32) -> i32 {
+        sqlx::query_as::<_, ValueInt>(&format!(
+            "select {STATION_OF} from request join dish on dish.id = request.dish left join species on species.id = dish.species where request.id = $1;"
+        ))
+            .bind(request_id)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .map(|x| x.0 as i32)
+            .unwrap_or(-1)
+    }
+
+    pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Station> {
+        sqlx::query_as("select * from station;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[get("/<name>")]
+pub async fn create(db: &State<Pool<Sqlite>>, name: String) -> String {
+    Station::create(db.inner(), decode_uri(name)).await.to_string()
+}
+
+#[get("/<id>")]
+pub async fn delete(db: &State<Pool<Sqlite>>, id: i32) -> String {
+    Station::delete(db.inner(), id).await.to_string()
+}
+
+#[get("/<id>/<name>")]
+pub async fn edit(db: &State<Pool<Sqlite>>, id: i32, name: String) -> String {
+    Station::edit(db.inner(), id, decode_uri(name)).await.to_string()
+}
+
+#[get("/<species>/<station>")]
+pub async fn assign_species(db: &State<Pool<Sqlite>>, species: i32, station: i32) -> String {
+    Station::assign_species(db.inner(), species, station).await.to_string()
+}
+
+#[get("/<dish>/<station>")]
+pub async fn assign_dish(db: &State<Pool<Sqlite>>, dish: i32, station: i32) -> String {
+    Station::assign_dish(db.inner(), dish, station).await.to_string()
+}
+
+#[get("/")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Station::fetch_all(db.inner()).await).unwrap()
+}
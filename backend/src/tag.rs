@@ -0,0 +1,319 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Executor, Pool, Sqlite, SqliteConnection};
+
+use crate::{callback_result::Result, dish::Dish, request::Request, utils::decode_uri};
+
+#[derive(FromRow, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tag {
+    pub id: i32,
+    pub name: String,
+    pub kind: i32
+    // 0 -> allergen (gluten, nuts, dairy, ...)
+    // 1 -> dietary (vegan, halal, vegetarian, ...)
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct OptionTag {
+    pub dish: i32,
+    pub variant: i32,
+    pub option: i32,
+    // index into Dish.variants, then index into that variant's options
+    pub tag: i32,
+    pub removes: bool
+    // true -> picking this option takes the tag off the dish ("no cheese" removes dairy)
+    // false -> picking this option adds the tag ("extra peanuts" adds nuts)
+}
+
+impl Tag {
+    // CREATE TABLE tag(id integer primary key autoincrement, name varchar, kind int);
+    // CREATE TABLE dish_tag(dish int, tag int);
+    // CREATE TABLE option_tag(dish int, variant int, option int, tag int, removes int);
+
+    pub async fn create(db: &Pool<Sqlite>, name: String, kind: i32) -> Result {
+        match Tag::fetch_by_name(db, &name).await {
+            Some(_) => Result::Exists,
+            None => {
+                sqlx::query("insert into tag(name, kind) values($1, $2);")
+                    .bind(Tag::normalize_name(&name))
+                    .bind(kind)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            }
+        }
+    }
+
+    pub async fn delete(db: &Pool<Sqlite>, id: i32) -> Result {
+        match Tag::fetch(db, id).await {
+            Some(_) => {
+                sqlx::query("delete from dish_tag where tag = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                sqlx::query("delete from option_tag where tag = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                sqlx::query("delete from tag where id = $1;")
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn edit(db: &Pool<Sqlite>, id: i32, name: String, kind: i32) -> Result {
+        if Tag::fetch_by_name(db, &name).await.is_some_and(|t| t.id != id) {
+            return Result::Exists;
+        }
+
+        match Tag::fetch(db, id).await {
+            Some(_) => {
+                sqlx::query("update tag set name = $1, kind = $2 where id = $3;")
+                    .bind(Tag::normalize_name(&name))
+                    .bind(kind)
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .unwrap();
+
+                Result::Success
+            },
+            None => Result::DoesntExist
+        }
+    }
+
+    pub async fn fetch<'e, E: Executor<'e, Database = Sqlite>>(db: E, id: i32) -> Option<Tag> {
+        match sqlx::query_as("select * from tag where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(t) => Some(t),
+            Err(e) => {
+                println!("tag.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_by_name<'e, E: Executor<'e, Database = Sqlite>>(db: E, name: &str) -> Option<Tag> {
+        match sqlx::query_as("select * from tag where name = $1;")
+            .bind(Tag::normalize_name(name))
+            .fetch_one(db)
+            .await {
+            Ok(t) => Some(t),
+            Err(e) => {
+                println!("tag.rs; fetch_by_name({name}); error: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_all(db: &Pool<Sqlite>) -> Vec<Tag> {
+        sqlx::query_as("select * from tag;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_for_dish<'e, E: Executor<'e, Database = Sqlite>>(db: E, dish: i32) -> Vec<Tag> {
+        sqlx::query_as("select tag.* from tag join dish_tag on tag.id = dish_tag.tag where dish_tag.dish = $1;")
+            .bind(dish)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_options_for_dish<'e, E: Executor<'e, Database = Sqlite>>(db: E, dish: i32) -> Vec<OptionTag> {
+        sqlx::query_as("select * from option_tag where dish = $1;")
+            .bind(dish)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn assign(db: &Pool<Sqlite>, dish: i32, tag: i32) -> Result {
+        if Dish::fetch(db, dish).await.is_none() || Tag::fetch(db, tag).await.is_none() {
+            return Result::DoesntExist;
+        }
+
+        if Tag::fetch_for_dish(db, dish).await.iter().any(|t| t.id == tag) {
+            return Result::Exists;
+        }
+
+        sqlx::query("insert into dish_tag(dish, tag) values($1, $2);")
+            .bind(dish)
+            .bind(tag)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn unassign(db: &Pool<Sqlite>, dish: i32, tag: i32) -> Result {
+        let affected = sqlx::query("delete from dish_tag where dish = $1 and tag = $2;")
+            .bind(dish)
+            .bind(tag)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+
+        if affected == 0 {
+            return Result::DoesntExist;
+        }
+
+        Result::Success
+    }
+
+    pub async fn assign_option(db: &Pool<Sqlite>, dish: i32, variant: i32, option: i32, tag: i32, removes: bool) -> Result {
+        let d = match Dish::fetch(db, dish).await {
+            Some(d) => d,
+            None => {
+                return Result::DoesntExist;
+            }
+        };
+
+        if Tag::fetch(db, tag).await.is_none() {
+            return Result::DoesntExist;
+        }
+
+        // option has to point at something that actually exists on the dish
+        let variants = Request::parse_variants(&d.variants);
+        if variant < 0 || option < 0 || variant as usize >= variants.len() || option as usize >= variants[variant as usize].1.len() {
+            return Result::VariantDoesntExist;
+        }
+
+        sqlx::query("delete from option_tag where dish = $1 and variant = $2 and option = $3 and tag = $4;")
+            .bind(dish)
+            .bind(variant)
+            .bind(option)
+            .bind(tag)
+            .execute(db)
+            .await
+            .unwrap();
+
+        sqlx::query("insert into option_tag(dish, variant, option, tag, removes) values($1, $2, $3, $4, $5);")
+            .bind(dish)
+            .bind(variant)
+            .bind(option)
+            .bind(tag)
+            .bind(removes)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn unassign_option(db: &Pool<Sqlite>, dish: i32, variant: i32, option: i32, tag: i32) -> Result {
+        let affected = sqlx::query("delete from option_tag where dish = $1 and variant = $2 and option = $3 and tag = $4;")
+            .bind(dish)
+            .bind(variant)
+            .bind(option)
+            .bind(tag)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+
+        if affected == 0 {
+            return Result::DoesntExist;
+        }
+
+        Result::Success
+    }
+
+    pub async fn effective(db: &mut SqliteConnection, dish: i32, variant: &[Option<usize>]) -> Vec<Tag> {
+        // dish tags, then apply whatever the chosen options add or take away
+        let mut tags = Tag::fetch_for_dish(&mut *db, dish).await;
+
+        for o in Tag::fetch_options_for_dish(&mut *db, dish).await {
+            let chosen = variant.get(o.variant as usize).copied().flatten() == Some(o.option as usize);
+            if !chosen {
+                continue;
+            }
+
+            if o.removes {
+                tags.retain(|t| t.id != o.tag);
+            } else if !tags.iter().any(|t| t.id == o.tag) {
+                if let Some(t) = Tag::fetch(&mut *db, o.tag).await {
+                    tags.push(t);
+                }
+            }
+        }
+
+        tags
+    }
+
+    pub fn normalize_name(name: &str) -> String {
+        // names are stored like this, and whatever a guest or admin typed is looked up like this
+        name.trim().to_lowercase()
+    }
+
+    pub fn parse_names(s: &str) -> Vec<String> {
+        // "nuts,dairy" -> ["nuts", "dairy"]
+        s.split(',')
+            .map(Tag::normalize_name)
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+}
+
+#[get("/<name>/<kind>")]
+pub async fn create(db: &State<Pool<Sqlite>>, name: String, kind: i32) -> String {
+    Tag::create(db.inner(), decode_uri(name), kind).await.to_string()
+}
+
+#[get("/<id>")]
+pub async fn delete(db: &State<Pool<Sqlite>>, id: i32) -> String {
+    Tag::delete(db.inner(), id).await.to_string()
+}
+
+#[get("/<id>/<name>/<kind>")]
+pub async fn edit(db: &State<Pool<Sqlite>>, id: i32, name: String, kind: i32) -> String {
+    Tag::edit(db.inner(), id, decode_uri(name), kind).await.to_string()
+}
+
+#[get("/<dish>/<tag>")]
+pub async fn assign(db: &State<Pool<Sqlite>>, dish: i32, tag: i32) -> String {
+    Tag::assign(db.inner(), dish, tag).await.to_string()
+}
+
+#[get("/<dish>/<tag>")]
+pub async fn unassign(db: &State<Pool<Sqlite>>, dish: i32, tag: i32) -> String {
+    Tag::unassign(db.inner(), dish, tag).await.to_string()
+}
+
+#[get("/<dish>/<variant>/<option>/<tag>/<removes>")]
+pub async fn assign_option(db: &State<Pool<Sqlite>>, dish: i32, variant: i32, option: i32, tag: i32, removes: bool) -> String {
+    Tag::assign_option(db.inner(), dish, variant, option, tag, removes).await.to_string()
+}
+
+#[get("/<dish>/<variant>/<option>/<tag>")]
+pub async fn unassign_option(db: &State<Pool<Sqlite>>, dish: i32, variant: i32, option: i32, tag: i32) -> String {
+    Tag::unassign_option(db.inner(), dish, variant, option, tag).await.to_string()
+}
+
+#[get("/")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>) -> String {
+    serde_json::to_string(&Tag::fetch_all(db.inner()).await).unwrap()
+}
+
+#[get("/<dish>/<variant>")]
+pub async fn effective(db: &State<Pool<Sqlite>>, dish: i32, variant: String) -> String {
+    let variant = Request::parse_variant_selection(&decode_uri(variant));
+    serde_json::to_string(&Tag::effective(&mut db.acquire().await.unwrap(), dish, &variant).await).unwrap()
+}
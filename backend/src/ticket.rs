@@ -0,0 +1,158 @@
+use sqlx::{Pool, Sqlite};
+
+use crate::{allergy::Allergy, dish::Dish, request::{Request, RUSH, VIP}, utils::format_time};
+
+pub const WIDTH: usize = 42;
+// characters per line in font A on 80mm paper
+
+pub struct EscPos {
+    pub bytes: Vec<u8>
+}
+impl EscPos {
+    pub fn new() -> EscPos {
+        // ESC @, reset the printer
+        EscPos { bytes: vec![0x1b, 0x40] }
+    }
+
+    pub fn align(mut self, align: u8) -> EscPos {
+        // ESC a n, 0 -> left, 1 -> centre, 2 -> right
+        self.bytes.extend([0x1b, 0x61, align]);
+        self
+    }
+
+    pub fn bold(mut self, on: bool) -> EscPos {
+        // ESC E n
+        self.bytes.extend([0x1b, 0x45, on as u8]);
+        self
+    }
+
+    pub fn size(mut self, width: u8, height: u8) -> EscPos {
+        // GS ! n, both 1..=8
+        self.bytes.extend([0x1d, 0x21, ((width.clamp(1, 8) - 1) << 4) | (height.clamp(1, 8) - 1)]);
+        self
+    }
+
+    pub fn text(mut self, s: &str) -> EscPos {
+        // the default code page is plain ascii, anything else would print as garbage
+        self.bytes.extend(s.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' }));
+        self
+    }
+
+    pub fn line(self, s: &str) -> EscPos {
+        let mut e = self.text(s);
+        e.bytes.push(b'\n');
+        e
+    }
+
+    pub fn rule(self) -> EscPos {
+        self.line(&"-".repeat(WIDTH))
+    }
+
+    pub fn feed(mut self, lines: u8) -> EscPos {
+        // ESC d n
+        self.bytes.extend([0x1b, 0x64, lines]);
+        self
+    }
+
+    pub fn cut(mut self) -> EscPos {
+        // GS V 66 0, feed to the cutter then partial cut
+        self.bytes.extend([0x1d, 0x56, 0x42, 0x00]);
+        self
+    }
+}
+
+pub struct TicketLine {
+    pub quantity: i32,
+    pub dish: String,
+    pub size: String,
+    pub options: Vec<String>,
+    pub comment: String,
+    pub priority: i32,
+    pub allergies: Vec<String>,
+    pub conflicts: Vec<String>
+}
+
+pub struct Ticket;
+impl Ticket {
+    pub async fn lines(db: &Pool<Sqlite>, requests: &[Request]) -> Vec<TicketLine> {
+        let mut result = vec![];
+        for r in requests {
+            let (allergies, conflicts) = Allergy::of(db, r).await;
+            let dish = Dish::fetch(db, r.dish).await;
+            let (name, size, options) = match &dish {
+                Some(d) => {
+                    let size = d.sizes.split(',').nth(r.size as usize).unwrap_or("").to_string();
+
+                    // older dishes dont hold json variants, those just print without options
+                    let variants = serde_json::from_str::<Vec<(bool, Vec<String>)>>(&d.variants).unwrap_or_default();
+                    let selection = serde_json::from_str::<Vec<Option<usize>>>(&r.variant).unwrap_or_default();
+                    let options = selection.iter()
+                        .enumerate()
+                        .filter_map(|(i, o)| o.and_then(|o| variants.get(i).and_then(|v| v.1.get(o)).cloned()))
+                        .collect();
+
+                    (d.name.clone(), size, options)
+                },
+                None => (format!("dish #{}", r.dish), String::new(), vec![])
+            };
+
+            result.push(TicketLine {
+                quantity: r.quantity,
+                dish: name,
+                size,
+                options,
+                comment: r.comment.clone(),
+                priority: r.priority,
+                allergies: allergies.into_iter().map(|t| t.name).collect(),
+                conflicts: conflicts.into_iter().map(|t| t.name).collect()
+            });
+        }
+        result
+    }
+
+    pub fn render(desk: &str, heading: &str, time: i32, lines: &[TicketLine]) -> Vec<u8> {
+        // heading is the station name, or "reprint" etc
+        let mut e = EscPos::new()
+            .align(1)
+            .size(2, 2)
+            .bold(true)
+            .line(&format!("TABLE {desk}"))
+            .size(1, 1)
+            .bold(false)
+            .line(heading)
+            .line(&format_time(time))
+            .align(0)
+            .rule();
+
+        for l in lines {
+            let title = if l.size.is_empty() {
+                format!("{} x {}", l.quantity, l.dish)
+            } else {
+                format!("{} x {} ({})", l.quantity, l.dish, l.size)
+            };
+            match l.priority {
+                RUSH => e = e.bold(true).line("*** RUSH ***").bold(false),
+                VIP => e = e.bold(true).line("*** VIP ***").bold(false),
+                _ => {}
+            }
+            e = e.size(1, 2).bold(true).line(&title).size(1, 1).bold(false);
+
+            // allergies go right under the dish, before anything else can push them off
+            if !l.conflicts.is_empty() {
+                e = e.size(1, 2).bold(true).line(&format!("   !! CONTAINS {}", l.conflicts.join(", ").to_uppercase())).size(1, 1).bold(false);
+            }
+            if !l.allergies.is_empty() {
+                e = e.bold(true).line(&format!("   ALLERGY: {}", l.allergies.join(", ").to_uppercase())).bold(false);
+            }
+
+            for o in l.options.iter() {
+                e = e.line(&format!("   - {o}"));
+            }
+            if !l.comment.is_empty() {
+                e = e.bold(true).line(&format!("   ! {}", l.comment)).bold(false);
+            }
+        }
+
+        e.rule().feed(3).cut().bytes
+    }
+}
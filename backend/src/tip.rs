@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{bill::{apply_rate, Bill, BillConfig}, callback_result::Result, desk::Desk, payment::COMPLETED, session::Session, split::allocate, staff, utils::{decode_uri, get_time, ValueString}, validation::Validation};
+
+pub const SUGGESTIONS: [i32; 3] = [1000, 1500, 2000];
+// basis points offered to the guest, worked out on what is left to pay
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TipSuggestion {
+    pub rate: i32,
+    pub amount: i64
+}
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct SessionTips {
+    pub session: i32,
+    pub tips: i64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TipShare {
+    pub staff: String,
+    pub amount: i64,
+    pub sessions: i32
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TipPool {
+    pub from: i32,
+    pub to: i32,
+    pub total: i64,
+    pub shares: Vec<TipShare>,
+    pub unassigned: i64
+    // tips from tables nobody was recorded serving
+}
+
+pub fn resolve(amount: i64, tip: Option<i64>, tip_rate: Option<i32>) -> std::result::Result<i64, Result> {
+    // a fixed tip, or a rate on the amount being paid, not both
+    match (tip, tip_rate) {
+        (Some(_), Some(_)) => Err(Result::TipInvalid),
+        (Some(t), None) if t >= 0 => Ok(t),
+        (None, Some(r)) if (0..=10000).contains(&r) => Ok(apply_rate(amount, r)),
+        (None, None) => Ok(0),
+        _ => Err(Result::TipInvalid)
+    }
+}
+
+pub struct Tip;
+impl Tip {
+    // CREATE TABLE session_staff(session int, staff varchar, time int, primary key(session, staff));
+    // who served which table
+
+    pub async fn suggest(db: &Pool<Sqlite>, desk: &str, config: &BillConfig) -> Option<Vec<TipSuggestion>> {
+        let bill = Bill::current(db, desk, config).await?;
+        Some(SUGGESTIONS.iter()
+            .map(|r| TipSuggestion { rate: *r, amount: apply_rate(bill.outstanding.max(0), *r) })
+            .collect())
+    }
+
+    pub async fn serve(db: &Pool<Sqlite>, session: i32, staff: &str) {
+        sqlx::query("insert or ignore into session_staff(session, staff, time) values($1, $2, $3);")
+            .bind(session)
+            .bind(staff)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn servers(db: &Pool<Sqlite>, session: i32) -> Vec<String> {
+        sqlx::query_as::<_, ValueString>("select staff from session_staff where session = $1 order by staff;")
+            .bind(session)
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect()
+    }
+
+    pub async fn pool(db: &Pool<Sqlite>, from: i32, to: i32) -> TipPool {
+        // from, to -> the shift, tips count by when they were paid
+        // each table's tips are shared evenly between whoever served it
+        // less any tip given back, see refund.rs
+        let sessions = sqlx::query_as::<_, SessionTips>("select session, sum(tip) as tips from (select session, tip from payment where status = $3 and tip > 0 and time >= $1 and time < $2 union all select session, -tip from refund where status = $3 and tip > 0 and time >= $1 and time < $2) group by session having sum(tip) > 0 order by session;")
+            .bind(from)
+            .bind(to)
+            .bind(COMPLETED)
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut shares = BTreeMap::<String, (i64, i32)>::new();
+        let mut unassigned = 0;
+        for s in sessions.iter() {
+            let servers = Tip::servers(db, s.session).await;
+            if servers.is_empty() {
+                unassigned += s.tips;
+                continue;
+            }
+
+            for (name, amount) in servers.iter().zip(allocate(s.tips, &vec![1; servers.len()], s.session as usize)) {
+                let share = shares.entry(name.clone()).or_default();
+                share.0 += amount;
+                share.1 += 1;
+            }
+        }
+
+        TipPool {
+            from,
+            to,
+            total: sessions.iter().map(|s| s.tips).sum(),
+            shares: shares.into_iter().map(|(staff, (amount, sessions))| TipShare { staff, amount, sessions }).collect(),
+            unassigned
+        }
+    }
+}
+
+#[post("/", data="<table>")]
+pub async fn suggest(db: &State<Pool<Sqlite>>, config: &State<BillConfig>, table: String) -> String {
+    let db = db.inner();
+    match Desk::fetch(db, &table).await {
+        Some(d) => serde_json::to_string(&Tip::suggest(db, &d.name, config.inner()).await).unwrap(),
+        None => Result::NoTable.to_string()
+    }
+}
+
+#[post("/<desk>", data="<login>")]
+pub async fn serve(db: &State<Pool<Sqlite>>, login: String, desk: String) -> String {
+    // a waiter picking up a table, taking a payment there does the same
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &[staff::WAITER, staff::MANAGER]).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    match Session::current(db, &decode_uri(desk)).await {
+        Some(s) => {
+            Tip::serve(db, s.id, &login.id).await;
+            Result::Success.to_string()
+        },
+        None => Result::DoesntExist.to_string()
+    }
+}
+
+#[get("/?<from>&<to>")]
+pub async fn pool(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Tip::pool(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}
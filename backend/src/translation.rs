@@ -0,0 +1,292 @@
+use rocket::{request::{FromRequest, Outcome}, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, dish::Dish, species::Species, utils::decode_uri};
+
+pub const DEFAULT_LOCALE: &str = "en";
+pub const LOCALES: [&str; 3] = ["en", "ms", "zh"];
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Translation {
+    pub kind: i32,
+    // 0 -> species name
+    // 1 -> dish name
+    // 2 -> dish size label
+    // 3 -> dish variant option label
+    pub target: i32,
+    // species id for kind 0, dish id otherwise
+    pub variant: i32,
+    pub item: i32,
+    // kind 2 -> variant = -1, item = size index
+    // kind 3 -> variant = variant index, item = option index
+    // otherwise both -1
+    pub locale: String,
+    pub text: String
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissingTranslation {
+    pub kind: i32,
+    pub target: i32,
+    pub variant: i32,
+    pub item: i32,
+    pub locale: String,
+    pub original: String
+}
+
+pub struct Locale(pub String);
+// picked from ?lang=, then Accept-Language, then DEFAULT_LOCALE
+
+impl Locale {
+    pub fn pick(s: &str) -> Option<String> {
+        // "ms-MY,ms;q=0.9,en;q=0.8" -> "ms"
+        // order is taken as given, q values arent weighed
+        s.split(',')
+            .map(|x| x.split(';').next().unwrap_or("").trim().to_lowercase())
+            .map(|x| x.split('-').next().unwrap_or("").to_string())
+            .find(|x| LOCALES.contains(&x.as_str()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Locale {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let from_query = request.query_value::<String>("lang")
+            .and_then(|x| x.ok())
+            .and_then(|x| Locale::pick(&x));
+        let from_header = request.headers().get_one("Accept-Language")
+            .and_then(Locale::pick);
+
+        Outcome::Success(Locale(from_query.or(from_header).unwrap_or(DEFAULT_LOCALE.to_string())))
+    }
+}
+
+impl Translation {
+    // CREATE TABLE translation(kind int, target int, variant int, item int, locale varchar, text varchar);
+
+    pub async fn set(db: &Pool<Sqlite>, kind: i32, target: i32, variant: i32, item: i32, locale: String, text: String) -> Result {
+        if !LOCALES.contains(&locale.as_str()) {
+            return Result::LocaleDoesntExist;
+        }
+
+        if Translation::original(db, kind, target, variant, item).await.is_none() {
+            return Result::DoesntExist;
+        }
+
+        sqlx::query("delete from translation where kind = $1 and target = $2 and variant = $3 and item = $4 and locale = $5;")
+            .bind(kind)
+            .bind(target)
+            .bind(variant)
+            .bind(item)
+            .bind(&locale)
+            .execute(db)
+            .await
+            .unwrap();
+
+        sqlx::query("insert into translation(kind, target, variant, item, locale, text) values($1, $2, $3, $4, $5, $6);")
+            .bind(kind)
+            .bind(target)
+            .bind(variant)
+            .bind(item)
+            .bind(locale)
+            .bind(text)
+            .execute(db)
+            .await
+            .unwrap();
+
+        Result::Success
+    }
+
+    pub async fn delete(db: &Pool<Sqlite>, kind: i32, target: i32, variant: i32, item: i32, locale: String) -> Result {
+        let affected = sqlx::query("delete from translation where kind = $1 and target = $2 and variant = $3 and item = $4 and locale = $5;")
+            .bind(kind)
+            .bind(target)
+            .bind(variant)
+            .bind(item)
+            .bind(locale)
+            .execute(db)
+            .await
+            .unwrap()
+            .rows_affected();
+
+        if affected == 0 {
+            return Result::DoesntExist;
+        }
+
+        Result::Success
+    }
+
+    pub async fn delete_species(db: &Pool<Sqlite>, species: i32) {
+        sqlx::query("delete from translation where kind = 0 and target = $1;")
+            .bind(species)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn delete_dish(db: &Pool<Sqlite>, dish: i32) {
+        sqlx::query("delete from translation where kind != 0 and target = $1;")
+            .bind(dish)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn fetch_for(db: &Pool<Sqlite>, kind: i32, target: i32) -> Vec<Translation> {
+        // kind 1, 2 and 3 all hang off the dish, so asking for a dish returns the lot
+        let query = if kind == 0 {
+            "select * from translation where kind = 0 and target = $1;"
+        } else {
+            "select * from translation where kind != 0 and target = $1;"
+        };
+
+        sqlx::query_as(query)
+            .bind(target)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn original(db: &Pool<Sqlite>, kind: i32, target: i32, variant: i32, item: i32) -> Option<String> {
+        // the untranslated text, which doubles as the default locale
+        if kind == 0 {
+            return Species::fetch(db, target).await.map(|s| s.name);
+        }
+
+        let dish = Dish::fetch(db, target).await?;
+        match kind {
+            1 => Some(dish.name),
+            2 => dish.sizes.split(',').nth(item.max(0) as usize).filter(|_| item >= 0).map(|x| x.to_string()),
+            3 => {
+                let variants = Translation::parse_variants(&dish.variants)?;
+                variants.get(variant.max(0) as usize)
+                    .filter(|_| variant >= 0 && item >= 0)
+                    .and_then(|v| v.1.get(item as usize))
+                    .cloned()
+            },
+            _ => None
+        }
+    }
+
+    fn parse_variants(v: &str) -> Option<Vec<(bool, Vec<String>)>> {
+        // older rows dont hold json, leave those untouched instead of panicking
+        serde_json::from_str(v).ok()
+    }
+
+    fn lookup(translations: &[Translation], kind: i32, variant: i32, item: i32, locale: &str) -> Option<String> {
+        translations.iter()
+            .find(|t| t.kind == kind && t.variant == variant && t.item == item && t.locale == locale)
+            .or(translations.iter().find(|t| t.kind == kind && t.variant == variant && t.item == item && t.locale == DEFAULT_LOCALE))
+            .map(|t| t.text.clone())
+    }
+
+    pub async fn localise_species(db: &Pool<Sqlite>, mut species: Species, locale: &str) -> Species {
+        let translations = Translation::fetch_for(db, 0, species.id).await;
+        if let Some(t) = Translation::lookup(&translations, 0, -1, -1, locale) {
+            species.name = t;
+        }
+        species
+    }
+
+    pub async fn localise_dish(db: &Pool<Sqlite>, mut dish: Dish, locale: &str) -> Dish {
+        let translations = Translation::fetch_for(db, 1, dish.id).await;
+        if translations.is_empty() {
+            return dish;
+        }
+
+        if let Some(t) = Translation::lookup(&translations, 1, -1, -1, locale) {
+            dish.name = t;
+        }
+
+        dish.sizes = dish.sizes.split(',')
+            .enumerate()
+            .map(|(i, s)| Translation::lookup(&translations, 2, -1, i as i32, locale).unwrap_or(s.to_string()))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        if let Some(mut variants) = Translation::parse_variants(&dish.variants) {
+            for (v, (_, options)) in variants.iter_mut().enumerate() {
+                for (o, option) in options.iter_mut().enumerate() {
+                    if let Some(t) = Translation::lookup(&translations, 3, v as i32, o as i32, locale) {
+                        *option = t;
+                    }
+                }
+            }
+            dish.variants = serde_json::to_string(&variants).unwrap();
+        }
+
+        dish
+    }
+
+    pub async fn missing(db: &Pool<Sqlite>, locales: Vec<String>) -> Vec<MissingTranslation> {
+        // every translatable string that has no entry for the given locales
+        let translations = sqlx::query_as::<_, Translation>("select * from translation;")
+            .fetch_all(db)
+            .await
+            .unwrap();
+
+        let mut wanted: Vec<(i32, i32, i32, i32, String)> = vec![];
+        for s in Species::fetch_all(db).await {
+            wanted.push((0, s.id, -1, -1, s.name));
+        }
+        for d in Dish::fetch_all(db).await {
+            wanted.push((1, d.id, -1, -1, d.name.clone()));
+            for (i, s) in d.sizes.split(',').enumerate() {
+                wanted.push((2, d.id, -1, i as i32, s.to_string()));
+            }
+            for (v, (_, options)) in Translation::parse_variants(&d.variants).unwrap_or_default().into_iter().enumerate() {
+                for (o, option) in options.into_iter().enumerate() {
+                    wanted.push((3, d.id, v as i32, o as i32, option));
+                }
+            }
+        }
+
+        let mut result = vec![];
+        for locale in locales {
+            for (kind, target, variant, item, original) in wanted.iter() {
+                let found = translations.iter().any(|t|
+                    t.kind == *kind && t.target == *target && t.variant == *variant && t.item == *item && t.locale == locale
+                );
+                if !found {
+                    result.push(MissingTranslation {
+                        kind: *kind,
+                        target: *target,
+                        variant: *variant,
+                        item: *item,
+                        locale: locale.clone(),
+                        original: original.clone()
+                    });
+                }
+            }
+        }
+        result
+    }
+}
+
+#[get("/<kind>/<target>/<variant>/<item>/<locale>/<text>")]
+pub async fn set(db: &State<Pool<Sqlite>>, kind: i32, target: i32, variant: i32, item: i32, locale: String, text: String) -> String {
+    Translation::set(db.inner(), kind, target, variant, item, decode_uri(locale), decode_uri(text)).await.to_string()
+}
+
+#[get("/<kind>/<target>/<variant>/<item>/<locale>")]
+pub async fn delete(db: &State<Pool<Sqlite>>, kind: i32, target: i32, variant: i32, item: i32, locale: String) -> String {
+    Translation::delete(db.inner(), kind, target, variant, item, decode_uri(locale)).await.to_string()
+}
+
+#[get("/<kind>/<target>")]
+pub async fn fetch(db: &State<Pool<Sqlite>>, kind: i32, target: i32) -> String {
+    serde_json::to_string(&Translation::fetch_for(db.inner(), kind, target).await).unwrap()
+}
+
+#[get("/?<locale>")]
+pub async fn missing(db: &State<Pool<Sqlite>>, locale: Option<String>) -> String {
+    // without a locale, report on every locale other than the default
+    let locales = match locale {
+        Some(l) => vec![l],
+        None => LOCALES.iter().filter(|x| **x != DEFAULT_LOCALE).map(|x| x.to_string()).collect()
+    };
+    serde_json::to_string(&Translation::missing(db.inner(), locales).await).unwrap()
+}
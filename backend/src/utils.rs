@@ -1,4 +1,4 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{sync::OnceLock, time::{SystemTime, UNIX_EPOCH}};
 
 use rand::prelude::*;
 use sqlx::prelude::FromRow;
@@ -8142,22 +8142,68 @@ pub fn get_time() -> i32 {
         .as_secs() as i32
 }
 
+pub const DEFAULT_UTC_OFFSET: i32 = 8 * 60 * 60;
+static UTC_OFFSET: OnceLock<i32> = OnceLock::new();
+
+pub fn set_utc_offset(offset: i32) {
+    // called once at launch from the "utc_offset" config
+    let _ = UTC_OFFSET.set(offset);
+}
+
+pub fn utc_offset() -> i32 {
+    // restaurant local time, everything printed or grouped by day uses this
+    *UTC_OFFSET.get().unwrap_or(&DEFAULT_UTC_OFFSET)
+}
+
+pub fn format_time(t: i32) -> String {
+    // unix seconds -> "2024-06-10 18:05" in local time
+    let t = t as i64 + utc_offset() as i64;
+    let days = t.div_euclid(86400);
+    let seconds = t.rem_euclid(86400);
+
+    // days since epoch -> civil date, see howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}", seconds / 3600, (seconds % 3600) / 60)
+}
+
+pub fn format_money(amount: i64) -> String {
+    // minor units -> "12.34"
+    let sign = if amount < 0 { "-" } else { "" };
+    format!("{sign}{}.{:02}", amount.abs() / 100, amount.abs() % 100)
+}
+
+pub fn format_rate(rate: i32) -> String {
+    // basis points -> "10%", "6.5%"
+    let whole = format!("{}.{:02}", rate / 100, rate % 100);
+    format!("{}%", whole.trim_end_matches('0').trim_end_matches('.'))
+}
+
 pub fn generate_name(rng: &mut ThreadRng) -> String {
     format!(
         "{}{}",
-        ADJECTIVES.to_string().split("\n").map(|x| x.to_string()).collect::<Vec<String>>()[
-            rng.gen_range(0..ADJECTIVES.to_string().split("\n").count())
+        ADJECTIVES.split("\n").map(|x| x.to_string()).collect::<Vec<String>>()[
+            rng.gen_range(0..ADJECTIVES.split("\n").count())
         ],
-        NOUNS.to_string().split("\n").map(|x| x.to_string()).collect::<Vec<String>>()[
-            rng.gen_range(0..NOUNS.to_string().split("\n").count())
+        NOUNS.split("\n").map(|x| x.to_string()).collect::<Vec<String>>()[
+            rng.gen_range(0..NOUNS.split("\n").count())
         ]
     )
 }
 
+#[allow(dead_code)]
 pub fn parse_response<T: serde::Serialize>(data: Result<T, T>) -> String {
     match data {
-        Ok(d) => format!(r#"{{"type":"success","data":"{}"}}"#, urlencoding::encode(serde_json::to_string(&d).unwrap().as_str()).to_string()),
-        Err(e) => format!(r#"{{"type":"fail","error":"{}"}}"#, urlencoding::encode(serde_json::to_string(&e).unwrap().as_str()).to_string())
+        Ok(d) => format!(r#"{{"type":"success","data":"{}"}}"#, urlencoding::encode(serde_json::to_string(&d).unwrap().as_str())),
+        Err(e) => format!(r#"{{"type":"fail","error":"{}"}}"#, urlencoding::encode(serde_json::to_string(&e).unwrap().as_str()))
     }
 }
 
@@ -8165,6 +8211,7 @@ pub fn decode_uri(s: String) -> String {
     urlencoding::decode(&s).unwrap().to_string()
 }
 
+#[allow(dead_code)]
 #[derive(FromRow, Debug)]
 pub struct Value(pub f64);
 // f64 doesnt implement FromRow for some reason???
@@ -1,11 +1,20 @@
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::{Pool, Sqlite};
 
 use crate::utils::{ValueInt, ValueString};
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Login {
+    pub id: String,
+    pub secret: String
+}
+// sent as the json body of staff routes, {"id":"...","secret":"..."}
+
 #[derive(Debug, Clone)]
 pub struct Validation;
 impl Validation {
+    #[allow(dead_code)]
     pub async fn table_hash(db: &Pool<Sqlite>, id: String) -> Option<String> {
         // checks for table hashes
         // if hash matches no table, return none
@@ -40,4 +49,46 @@ impl Validation {
             .unwrap()
             .0 > 0
     }
+
+    pub fn hash_secret(secret: &str) -> String {
+        hex::encode(Sha256::digest(secret.as_bytes()))
+    }
+
+    pub async fn verify_staff(db: &Pool<Sqlite>, login: &str, roles: &[i32]) -> bool {
+        // true if the login belongs to staff holding one of the roles
+        // admins pass for every role
+
+        match serde_json::from_str::<Login>(login) {
+            Ok(l) => Validation::verify_login(db, &l, roles).await,
+            Err(_) => false
+        }
+    }
+
+    pub async fn verified_login(db: &Pool<Sqlite>, login: &str, roles: &[i32]) -> Option<Login> {
+        // same as verify_staff, but hands back who it was
+        let l = serde_json::from_str::<Login>(login).ok()?;
+        if Validation::verify_login(db, &l, roles).await {
+            Some(l)
+        } else {
+            None
+        }
+    }
+
+    pub async fn verify_login(db: &Pool<Sqlite>, login: &Login, roles: &[i32]) -> bool {
+        if Validation::verify_admin(db, login.id.clone(), login.secret.clone()).await {
+            return true;
+        }
+
+        let role = sqlx::query_as::<_, ValueInt>("select role from staff where id = $1 and secret = $2;")
+            .bind(&login.id)
+            .bind(Validation::hash_secret(&login.secret))
+            .fetch_optional(db)
+            .await
+            .unwrap();
+
+        match role {
+            Some(r) => roles.contains(&(r.0 as i32)),
+            None => false
+        }
+    }
 }
@@ -0,0 +1,310 @@
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite};
+
+use crate::{callback_result::Result, events::{self, EventBus}, request::{Request, CANCELLED, COMPLETED, IN_KITCHEN, PENDING}, staff, utils::get_time, validation::{Login, Validation}};
+
+pub const REASONS: [&str; 6] = ["changed_mind", "wrong_order", "out_of_stock", "quality", "too_slow", "other"];
+// index is the reason code
+
+pub const AWAITING: i32 = 0;
+pub const APPROVED: i32 = 1;
+pub const REJECTED: i32 = 2;
+
+#[derive(FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct Void {
+    pub id: i32,
+    pub request: i32,
+    pub desk: String,
+    pub dish: i32,
+    pub size: i32,
+    pub quantity: i32,
+    pub state: i32,
+    // what the request was at when the void went through, in kitchen or completed means food was wasted
+    // read again on approval, the kitchen may have carried on while it waited for a manager
+    pub reason: i32,
+    pub note: String,
+    pub requested_by: Option<String>,
+    // staff id, None when the table cancelled it itself
+    pub approved_by: Option<String>,
+    pub status: i32,
+    // 0 -> awaiting a manager
+    // 1 -> approved, the request is cancelled
+    // 2 -> rejected, the request carries on
+    pub requested: i32,
+    pub decided: Option<i32>
+}
+
+#[derive(FromRow, Debug, Serialize, Deserialize)]
+pub struct WasteLine {
+    pub dish: i32,
+    pub reason: i32,
+    pub voids: i32,
+    pub quantity: i32,
+    pub wasted: i32
+    // quantity that had already reached the kitchen
+}
+
+impl Void {
+    // CREATE TABLE void(id integer primary key autoincrement, request int, desk varchar, dish int, size int, quantity int, state int, reason int, note varchar, requested_by varchar, approved_by varchar, status int, requested int, decided int);
+    // rows are never deleted, they are the audit trail
+
+    async fn open(db: &Pool<Sqlite>, request: &Request, reason: i32, note: String, by: Option<String>) -> (Result, Option<i32>) {
+        if reason < 0 || reason as usize >= REASONS.len() {
+            return (Result::ReasonDoesntExist, None);
+        }
+        if !(PENDING..=COMPLETED).contains(&request.state) {
+            return (Result::StateInvalid, None);
+        }
+        if Void::awaiting_for(db, request.id).await.is_some() {
+            return (Result::Exists, None);
+        }
+
+        let id = sqlx::query("insert into void(request, desk, dish, size, quantity, state, reason, note, requested_by, approved_by, status, requested, decided) values($1, $2, $3, $4, $5, $6, $7, $8, $9, null, $10, $11, null);")
+            .bind(request.id)
+            .bind(&request.desk)
+            .bind(request.dish)
+            .bind(request.size)
+            .bind(request.quantity)
+            .bind(request.state)
+            .bind(reason)
+            .bind(note)
+            .bind(by)
+            .bind(AWAITING)
+            .bind(get_time())
+            .execute(db)
+            .await
+            .unwrap()
+            .last_insert_rowid() as i32;
+
+        (Result::Success, Some(id))
+    }
+
+    async fn decide(db: &Pool<Sqlite>, id: i32, status: i32, by: Option<String>, until: i32) -> bool {
+        // until -> the latest state an approval can still cancel the request from
+        // false when an approval found the request past that, the void is left awaiting as it was
+        let mut tx = db.begin().await.unwrap();
+        let void: Void = sqlx::query_as("update void set status = $1, approved_by = $2, decided = $3, state = case when $1 = $5 then coalesce((select state from request where request.id = void.request), state) else state end where id = $4 returning *;")
+            .bind(status)
+            .bind(by)
+            .bind(get_time())
+            .bind(id)
+            .bind(APPROVED)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+
+        if status == APPROVED {
+            // the state check and the cancel are one statement, the kitchen can take the request in between otherwise
+            let cancelled = sqlx::query("update request set state = $1 where id = $2 and state between $3 and $4;")
+                .bind(CANCELLED)
+                .bind(void.request)
+                .bind(PENDING)
+                .bind(until)
+                .execute(&mut *tx)
+                .await
+                .unwrap()
+                .rows_affected();
+            if cancelled == 0 {
+                tx.rollback().await.unwrap();
+                return false;
+            }
+            tx.commit().await.unwrap();
+            Request::log_state(db, void.request, CANCELLED).await;
+            return true;
+        }
+
+        tx.commit().await.unwrap();
+        true
+    }
+
+    pub async fn by_table(db: &Pool<Sqlite>, desk: &str, request_id: i32, reason: i32) -> Result {
+        // tables can only take back what the kitchen hasnt accepted yet
+        let request = match Request::fetch(db, request_id).await {
+            Some(r) if r.desk == desk => r,
+            _ => {
+                return Result::DoesntExist;
+            }
+        };
+        if request.state != PENDING {
+            return Result::StateInvalid;
+        }
+
+        let (result, id) = Void::open(db, &request, reason, String::new(), None).await;
+        if let Some(id) = id {
+            // the kitchen took it since, the void stays with a manager like any other
+            if !Void::decide(db, id, APPROVED, None, PENDING).await {
+                return Result::StateInvalid;
+            }
+        }
+        result
+    }
+
+    pub async fn by_staff(db: &Pool<Sqlite>, login: &Login, request_id: i32, reason: i32, note: String) -> (Result, Option<Void>) {
+        // pending requests go straight through, anything accepted waits for a manager unless one asked
+        let request = match Request::fetch(db, request_id).await {
+            Some(r) => r,
+            None => {
+                return (Result::DoesntExist, None);
+            }
+        };
+
+        let (result, id) = Void::open(db, &request, reason, note, Some(login.id.clone())).await;
+        let id = match id {
+            Some(id) => id,
+            None => {
+                return (result, None);
+            }
+        };
+
+        // a pending one the kitchen takes in the meantime is left awaiting a manager
+        if Validation::verify_login(db, login, &[staff::MANAGER]).await {
+            Void::decide(db, id, APPROVED, Some(login.id.clone()), COMPLETED).await;
+        } else if request.state == PENDING {
+            Void::decide(db, id, APPROVED, Some(login.id.clone()), PENDING).await;
+        }
+        (Result::Success, Void::fetch(db, id).await)
+    }
+
+    pub async fn approve(db: &Pool<Sqlite>, id: i32, by: &str, approve: bool) -> (Result, Option<i32>) {
+        // returns the request that got cancelled
+        let void = match Void::fetch(db, id).await {
+            Some(v) => v,
+            None => {
+                return (Result::DoesntExist, None);
+            }
+        };
+        if void.status != AWAITING {
+            return (Result::StateInvalid, None);
+        }
+
+        // the request may have moved on since, but a cancelled one cant be cancelled twice
+        if !approve {
+            Void::decide(db, id, REJECTED, Some(by.to_string()), COMPLETED).await;
+            return (Result::Success, None);
+        }
+        if !Void::decide(db, id, APPROVED, Some(by.to_string()), COMPLETED).await {
+            Void::decide(db, id, REJECTED, Some(by.to_string()), COMPLETED).await;
+            return (Result::StateInvalid, None);
+        }
+        (Result::Success, Some(void.request))
+    }
+
+    pub async fn fetch(db: &Pool<Sqlite>, id: i32) -> Option<Void> {
+        match sqlx::query_as("select * from void where id = $1;")
+            .bind(id)
+            .fetch_one(db)
+            .await {
+            Ok(v) => Some(v),
+            Err(e) => {
+                println!("void.rs; fetch({id}); error: {e}");
+                None
+            }
+        }
+    }
+
+    async fn awaiting_for(db: &Pool<Sqlite>, request_id: i32) -> Option<Void> {
+        sqlx::query_as("select * from void where request = $1 and status = $2;")
+            .bind(request_id)
+            .bind(AWAITING)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn awaiting(db: &Pool<Sqlite>) -> Vec<Void> {
+        sqlx::query_as("select * from void where status = $1 order by id;")
+            .bind(AWAITING)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn fetch_range(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<Void> {
+        sqlx::query_as("select * from void where requested >= $1 and requested < $2 order by id;")
+            .bind(from)
+            .bind(to)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn waste(db: &Pool<Sqlite>, from: i32, to: i32) -> Vec<WasteLine> {
+        sqlx::query_as("select dish, reason, count(*) as voids, sum(quantity) as quantity, sum(case when state >= $3 then quantity else 0 end) as wasted from void where status = $4 and requested >= $1 and requested < $2 group by dish, reason order by wasted desc, quantity desc;")
+            .bind(from)
+            .bind(to)
+            .bind(IN_KITCHEN)
+            .bind(APPROVED)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+}
+
+#[post("/<request_id>/<reason>?<note>", data="<login>")]
+pub async fn request(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, request_id: i32, reason: i32, note: Option<String>) -> String {
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &staff::ROLES).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    let (result, void) = Void::by_staff(db, &login, request_id, reason, note.unwrap_or_default()).await;
+    match void {
+        Some(v) => {
+            if v.status == APPROVED {
+                bus.publish_id(db, events::CANCELLED, v.request).await;
+            }
+            serde_json::to_string(&v).unwrap()
+        },
+        None => result.to_string()
+    }
+}
+
+#[post("/<id>", data="<login>")]
+pub async fn approve(db: &State<Pool<Sqlite>>, bus: &State<EventBus>, login: String, id: i32) -> String {
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &[staff::MANAGER]).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    let (result, cancelled) = Void::approve(db, id, &login.id, true).await;
+    if let Some(r) = cancelled {
+        bus.publish_id(db, events::CANCELLED, r).await;
+    }
+    result.to_string()
+}
+
+#[post("/<id>", data="<login>")]
+pub async fn reject(db: &State<Pool<Sqlite>>, login: String, id: i32) -> String {
+    let db = db.inner();
+    let login = match Validation::verified_login(db, &login, &[staff::MANAGER]).await {
+        Some(l) => l,
+        None => {
+            return Result::NoPermission.to_string();
+        }
+    };
+    Void::approve(db, id, &login.id, false).await.0.to_string()
+}
+
+#[post("/", data="<login>")]
+pub async fn awaiting(db: &State<Pool<Sqlite>>, login: String) -> String {
+    let db = db.inner();
+    if !Validation::verify_staff(db, &login, &staff::ROLES).await {
+        return Result::NoPermission.to_string();
+    }
+    serde_json::to_string(&Void::awaiting(db).await).unwrap()
+}
+
+#[get("/?<from>&<to>")]
+pub async fn fetch_all(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Void::fetch_range(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}
+
+#[get("/?<from>&<to>")]
+pub async fn waste(db: &State<Pool<Sqlite>>, from: Option<i32>, to: Option<i32>) -> String {
+    serde_json::to_string(&Void::waste(db.inner(), from.unwrap_or(0), to.unwrap_or(i32::MAX)).await).unwrap()
+}